@@ -1,57 +1,1359 @@
 use std::fmt;
 use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::{Error, Result};
+use header::{CartridgeHeader, Mapper};
+use utils::{BitOps, WordOps};
 
 
 pub struct Cartridge {
     data: Vec<u8>,
-    title: String,
-    ram_size: u16,
+    ram: Vec<u8>,
+    mbc: Box<dyn Mbc>,
+    header: CartridgeHeader,
+    battery: bool,
+    /// Where battery-backed RAM is flushed to.
+    save_path: Option<PathBuf>,
 }
 
 impl Cartridge {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    /// Loads a cartridge from a ROM file. For battery-backed cartridges, the
+    /// save file is kept next to the ROM, with a `.sav` extension, and is
+    /// loaded if it exists.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
         let mut file = try!(File::open(path));
         let mut buffer = Vec::new();
         try!(file.read_to_end(&mut buffer));
-        Ok(Cartridge::from_buffer(buffer))
+
+        let mut cart = try!(Cartridge::from_buffer(buffer));
+        if cart.battery {
+            let save_path = path.with_extension("sav");
+            if save_path.exists() {
+                try!(cart.load_save(&save_path));
+            }
+            cart.save_path = Some(save_path);
+        }
+        Ok(cart)
     }
 
-    pub fn from_buffer(buffer: Vec<u8>) -> Self {
-        let title = String::from_utf8(
-            buffer[0x0134..0x0143].into_iter().cloned()
-                .take_while(|b| *b != 0x00).collect()).unwrap();
+    pub fn from_buffer(buffer: Vec<u8>) -> Result<Self> {
+        let header = try!(CartridgeHeader::from_rom(&buffer));
+        let cartridge_type = header.cartridge_type;
 
-        let ram_size = match buffer[0x0149] {
-            0x00 => 0,
-            0x01 => 2*1024,
-            0x02 => 8*1024,
-            0x03 => 32*1024,
-            _ => panic!("Invalid RAM size"),
+        let ram_size = match (cartridge_type.mapper, header.ram_size) {
+            // MBC2 has built-in RAM, which the header does not declare.
+            (Mapper::Mbc2, _) => MBC2_RAM_SIZE,
+            (_, Some(size)) => size,
+            (_, None) => return Err(Error::InvalidRamSize {
+                code: buffer[RAM_SIZE_ADDR],
+            }),
         };
 
-        Cartridge {
+        let mbc: Box<dyn Mbc> = match cartridge_type.mapper {
+            Mapper::RomOnly => Box::new(NoMbc),
+            Mapper::Mbc1 => Box::new(Mbc1::new(is_mbc1_multicart(&buffer))),
+            Mapper::Mbc2 => Box::new(Mbc2::new()),
+            Mapper::Mbc3 => Box::new(Mbc3::new(cartridge_type.timer)),
+            Mapper::Mbc5 => Box::new(Mbc5::new(cartridge_type.rumble)),
+            _ => return Err(Error::UnsupportedCartridge {
+                code: cartridge_type.code,
+            }),
+        };
+
+        Ok(Cartridge {
             data: buffer,
-            title: title,
-            ram_size: ram_size,
-        }
+            ram: vec![0; ram_size],
+            mbc: mbc,
+            battery: cartridge_type.battery,
+            header: header,
+            save_path: None,
+        })
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
     }
 
     pub fn read8(&self, addr: u16) -> u8 {
-        self.data[addr as usize]
+        self.mbc.read_rom(&self.data, addr)
+    }
+
+    /// Writes to the ROM area, which configures the memory bank controller.
+    pub fn write8(&mut self, addr: u16, val: u8) {
+        self.mbc.write_rom(addr, val);
+    }
+
+    /// Reads from external RAM, addressed relative to 0xA000.
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.mbc.read_ram(&self.ram, addr)
+    }
+
+    /// Writes to external RAM, addressed relative to 0xA000.
+    pub fn write_ram(&mut self, addr: u16, val: u8) {
+        self.mbc.write_ram(&mut self.ram, addr, val);
+    }
+
+    /// Advances any clock on the cartridge by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        self.mbc.tick(cycles);
+    }
+
+    /// Selects what drives the real-time clock of MBC3 cartridges. Has no
+    /// effect on cartridges without a clock.
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.mbc.set_rtc_clock(clock);
+    }
+
+    /// Registers a callback for rumble cartridges, called with the new
+    /// motor state whenever the game turns the motor on or off.
+    pub fn set_rumble_callback<F>(&mut self, callback: F)
+            where F: FnMut(bool) + 'static {
+        self.mbc.set_rumble_callback(Box::new(callback));
+    }
+
+    /// Whether the cartridge RAM is battery-backed, and so should persist
+    /// between sessions.
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// Sets where `flush_save` writes battery-backed RAM to.
+    pub fn set_save_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.save_path = Some(path.into());
+    }
+
+    /// Loads battery-backed RAM from a save file.
+    pub fn load_save<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut file = try!(File::open(path));
+        let mut buffer = Vec::new();
+        try!(file.read_to_end(&mut buffer));
+        self.load_save_data(&buffer);
+        Ok(())
+    }
+
+    /// Loads battery-backed RAM from the contents of a save file: the raw
+    /// RAM, followed by the clock state for cartridges with an RTC.
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let ram_len = self.ram.len().min(data.len());
+        self.ram[..ram_len].copy_from_slice(&data[..ram_len]);
+        let footer = &data[ram_len..];
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            if footer.len() >= RTC_FOOTER_MIN_SIZE {
+                rtc.load_footer(footer);
+            }
+        }
+    }
+
+    /// Returns the contents of a save file for the cartridge, in the layout
+    /// used by other emulators.
+    pub fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            data.extend(rtc.save_footer().iter());
+        }
+        data
+    }
+
+    /// Writes battery-backed RAM to the save path. Does nothing for
+    /// cartridges without a battery, or if there is nowhere to save to.
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        if !self.battery {
+            return Ok(());
+        }
+        let path = match self.save_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let data = self.save_data();
+        let mut file = try!(File::create(path));
+        file.write_all(&data)
     }
 
     pub fn title(&self) -> &str {
-        &self.title
+        &self.header.title
     }
 }
 
 impl fmt::Debug for Cartridge {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Cartridge")
-            .field("title", &self.title)
-            .field("ram_size", &self.ram_size)
+            .field("header", &self.header)
+            .field("mbc", &self.mbc)
             .finish()
     }
 }
+
+
+/// A memory bank controller, which maps banks of the cartridge ROM and RAM
+/// into the address space. ROM addresses are absolute (0x0000-0x7FFF); RAM
+/// addresses are relative to 0xA000.
+trait Mbc: fmt::Debug {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8;
+    fn write_rom(&mut self, addr: u16, val: u8);
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8;
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8);
+
+    fn tick(&mut self, _cycles: u8) {}
+    fn set_rtc_clock(&mut self, _clock: RtcClock) {}
+    fn set_rumble_callback(&mut self, _callback: Box<dyn FnMut(bool)>) {}
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> { None }
+}
+
+/// Reads a byte from the given 16 KiB ROM bank. Bank numbers beyond the end
+/// of the ROM wrap around, as the unused upper bank bits are not connected.
+fn rom_bank_byte(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    if rom.is_empty() {
+        return 0xFF;
+    }
+    rom[(bank * ROM_BANK_SIZE + (addr as usize & 0x3FFF)) % rom.len()]
+}
+
+/// Returns the offset of a byte in the given 8 KiB RAM bank, if the
+/// cartridge has any RAM.
+fn ram_bank_offset(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        None
+    } else {
+        Some((bank * RAM_BANK_SIZE + (addr as usize & 0x1FFF)) % ram.len())
+    }
+}
+
+
+/// A cartridge without a memory bank controller: 32 KiB of ROM and at most
+/// 8 KiB of RAM.
+#[derive(Debug)]
+struct NoMbc;
+
+impl Mbc for NoMbc {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        rom.get(addr as usize).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, _addr: u16, _val: u8) {}
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        ram_bank_offset(ram, 0, addr).map_or(0xFF, |i| ram[i])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        if let Some(i) = ram_bank_offset(ram, 0, addr) {
+            ram[i] = val;
+        }
+    }
+}
+
+
+/// The MBC1 controller: up to 2 MiB of ROM and 32 KiB of RAM.
+#[derive(Debug, Default)]
+struct Mbc1 {
+    ram_enabled: bool,
+    /// The lower five bits of the ROM bank number.
+    bank1: u8,
+    /// Two extra bits, used for the upper ROM bank bits or the RAM bank.
+    bank2: u8,
+    /// In advanced mode, `bank2` also applies to the 0x0000-0x3FFF ROM area
+    /// and to RAM.
+    advanced_mode: bool,
+    /// MBC1M multicarts wire `bank2` to ROM bank bits 4-5 instead of 5-6.
+    multicart: bool,
+}
+
+impl Mbc1 {
+    fn new(multicart: bool) -> Self {
+        Mbc1 {
+            bank1: 1,
+            multicart: multicart,
+            ..Default::default()
+        }
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn lower_rom_bank(&self) -> usize {
+        if self.advanced_mode {
+            (self.bank2 << self.bank2_shift()) as usize
+        } else {
+            0
+        }
+    }
+
+    fn upper_rom_bank(&self) -> usize {
+        let bank1 = if self.multicart { self.bank1 & 0xF } else { self.bank1 };
+        ((self.bank2 << self.bank2_shift()) | bank1) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_mode { self.bank2 as usize } else { 0 }
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 {
+            self.lower_rom_bank()
+        } else {
+            self.upper_rom_bank()
+        };
+        rom_bank_byte(rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = val & 0xF == 0xA,
+            0x2000...0x3FFF => {
+                // Bank 0 can't be selected; it is translated to bank 1.
+                self.bank1 = match val & 0x1F { 0 => 1, bank => bank };
+            }
+            0x4000...0x5FFF => self.bank2 = val & 0b11,
+            _ => self.advanced_mode = val & 0b1 == 1,
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        ram_bank_offset(ram, self.ram_bank(), addr).map_or(0xFF, |i| ram[i])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        if let Some(i) = ram_bank_offset(ram, self.ram_bank(), addr) {
+            ram[i] = val;
+        }
+    }
+}
+
+/// MBC1M multicarts are 1 MiB carts made of four 256 KiB games, each of
+/// which starts with its own header. They are detected by the Nintendo logo
+/// at the start of the second game.
+fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    const LOGO_START: usize = 0x0104;
+    const LOGO_END: usize = 0x0134;
+    const SECOND_GAME: usize = 0x10 * ROM_BANK_SIZE;
+
+    rom.len() == 64 * ROM_BANK_SIZE &&
+        rom[LOGO_START..LOGO_END] ==
+            rom[SECOND_GAME+LOGO_START..SECOND_GAME+LOGO_END]
+}
+
+
+
+/// The MBC2 controller: up to 256 KiB of ROM and 512 half-bytes of built-in
+/// RAM.
+#[derive(Debug, Default)]
+struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    fn new() -> Self {
+        Mbc2 {
+            rom_bank: 1,
+            ..Default::default()
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank as usize };
+        rom_bank_byte(rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        if addr >= 0x4000 {
+            return;
+        }
+        // Address bit 8 selects between the two registers.
+        if addr.get_bit(8) {
+            self.rom_bank = match val & 0xF { 0 => 1, bank => bank };
+        } else {
+            self.ram_enabled = val & 0xF == 0xA;
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // Only the lower nibble is stored; the upper nibble reads as 1s.
+        0xF0 | ram[addr as usize % MBC2_RAM_SIZE]
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        if self.ram_enabled {
+            ram[addr as usize % MBC2_RAM_SIZE] = val & 0xF;
+        }
+    }
+}
+
+
+/// The MBC3 controller: up to 2 MiB of ROM, 32 KiB of RAM and optionally a
+/// real-time clock.
+#[derive(Debug)]
+struct Mbc3 {
+    ram_enabled: bool,
+    rom_bank: u8,
+    /// Selects a RAM bank (0x00-0x03) or an RTC register (0x08-0x0C).
+    ram_bank: u8,
+    rtc: Option<Rtc>,
+    /// The last value written to the latch register. Writing 0x00 then 0x01
+    /// latches the clock.
+    last_latch_write: u8,
+}
+
+impl Mbc3 {
+    fn new(with_rtc: bool) -> Self {
+        Mbc3 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: if with_rtc { Some(Rtc::new()) } else { None },
+            last_latch_write: 0xFF,
+        }
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank as usize };
+        rom_bank_byte(rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = val & 0xF == 0xA,
+            0x2000...0x3FFF => {
+                self.rom_bank = match val & 0x7F { 0 => 1, bank => bank };
+            }
+            0x4000...0x5FFF => self.ram_bank = val & 0xF,
+            _ => {
+                if self.last_latch_write == 0x00 && val == 0x01 {
+                    if let Some(ref mut rtc) = self.rtc {
+                        rtc.latch();
+                    }
+                }
+                self.last_latch_write = val;
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (0x0...0x3, _) => ram_bank_offset(ram, self.ram_bank as usize, addr)
+                .map_or(0xFF, |i| ram[i]),
+            (0x8...0xC, &Some(ref rtc)) => rtc.read(self.ram_bank),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match (self.ram_bank, &mut self.rtc) {
+            (0x0...0x3, _) => {
+                if let Some(i) = ram_bank_offset(ram, self.ram_bank as usize,
+                                                 addr) {
+                    ram[i] = val;
+                }
+            }
+            (0x8...0xC, &mut Some(ref mut rtc)) => rtc.write(self.ram_bank, val),
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.set_clock(clock);
+        }
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+
+/// The MBC5 controller: up to 8 MiB of ROM and 128 KiB of RAM. On rumble
+/// cartridges, bit 3 of the RAM bank register drives the rumble motor
+/// instead.
+struct Mbc5 {
+    ram_enabled: bool,
+    /// The 9-bit ROM bank number. Unlike older controllers, bank 0 can be
+    /// mapped to 0x4000-0x7FFF.
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
+}
+
+impl Mbc5 {
+    fn new(has_rumble: bool) -> Self {
+        Mbc5 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble: has_rumble,
+            rumble: false,
+            rumble_callback: None,
+        }
+    }
+
+    fn set_rumble(&mut self, rumble: bool) {
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            if let Some(ref mut callback) = self.rumble_callback {
+                callback(rumble);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Mbc5 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Mbc5")
+            .field("ram_enabled", &self.ram_enabled)
+            .field("rom_bank", &self.rom_bank)
+            .field("ram_bank", &self.ram_bank)
+            .field("has_rumble", &self.has_rumble)
+            .field("rumble", &self.rumble)
+            .finish()
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank as usize };
+        rom_bank_byte(rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = val == 0x0A,
+            0x2000...0x2FFF => self.rom_bank.set_lower(val),
+            0x3000...0x3FFF => self.rom_bank.set_upper(val & 0b1),
+            0x4000...0x5FFF if self.has_rumble => {
+                self.ram_bank = val & 0b111;
+                self.set_rumble(val.get_bit(3));
+            }
+            0x4000...0x5FFF => self.ram_bank = val & 0xF,
+            _ => (),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        ram_bank_offset(ram, self.ram_bank as usize, addr)
+            .map_or(0xFF, |i| ram[i])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        if let Some(i) = ram_bank_offset(ram, self.ram_bank as usize, addr) {
+            ram[i] = val;
+        }
+    }
+
+    fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.rumble_callback = Some(callback);
+    }
+}
+
+
+/// What drives the MBC3 real-time clock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RtcClock {
+    /// The clock advances with emulated time, which keeps runs
+    /// deterministic.
+    Emulated,
+    /// The clock follows the host's wall clock.
+    Host,
+}
+
+impl Default for RtcClock {
+    fn default() -> Self { RtcClock::Emulated }
+}
+
+
+/// The MBC3 real-time clock. The game reads a latched copy of the counters,
+/// while writes go to the live counters.
+#[derive(Debug)]
+struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    /// The 9-bit day counter.
+    days: u16,
+    halted: bool,
+    /// Set when the day counter overflows; only cleared by the game.
+    day_carry: bool,
+    latched: [u8; 5],
+    clock: RtcClock,
+    /// M-cycles elapsed since the last whole second, for the emulated clock.
+    cycles: u32,
+    /// The host time the counters were last synchronised with, for the host
+    /// clock.
+    last_sync: SystemTime,
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halted: false,
+            day_carry: false,
+            latched: [0; 5],
+            clock: RtcClock::Emulated,
+            cycles: 0,
+            last_sync: SystemTime::now(),
+        }
+    }
+
+    /// Switches the clock source. When switching to the host clock, the
+    /// host time elapsed since the cartridge was loaded, or since its save
+    /// file was written, is applied on the next access.
+    fn set_clock(&mut self, clock: RtcClock) {
+        self.sync();
+        self.clock = clock;
+        self.cycles = 0;
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        if self.clock != RtcClock::Emulated || self.halted {
+            return;
+        }
+        self.cycles += cycles as u32;
+        if self.cycles >= RTC_CYCLES_PER_SECOND {
+            self.cycles -= RTC_CYCLES_PER_SECOND;
+            self.advance_second();
+        }
+    }
+
+    /// Catches the counters up with the host clock.
+    fn sync(&mut self) {
+        if self.clock != RtcClock::Host {
+            return;
+        }
+        let now = SystemTime::now();
+        let elapsed = now.duration_since(self.last_sync)
+            .unwrap_or(Duration::from_secs(0)).as_secs();
+        if self.halted {
+            self.last_sync = now;
+        } else {
+            self.last_sync += Duration::from_secs(elapsed);
+            self.advance(elapsed);
+        }
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // Skip over whole days at once, as long as the counters hold valid
+        // values and so will roll over normally.
+        if self.seconds < 60 && self.minutes < 60 && self.hours < 24 {
+            let days = self.days as u64 + seconds / SECONDS_PER_DAY;
+            if days > RTC_DAY_MASK as u64 {
+                self.day_carry = true;
+            }
+            self.days = (days & RTC_DAY_MASK as u64) as u16;
+            seconds %= SECONDS_PER_DAY;
+        }
+        for _ in 0..seconds {
+            self.advance_second();
+        }
+    }
+
+    /// Increments the counters by one second. Each counter only carries
+    /// into the next when it reaches its rollover value; a counter that was
+    /// set out of range instead wraps at its bit width without carrying.
+    fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days = (self.days + 1) & RTC_DAY_MASK;
+        if self.days == 0 {
+            self.day_carry = true;
+        }
+    }
+
+    fn latch(&mut self) {
+        self.sync();
+        self.latched = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            self.day_high(),
+        ];
+    }
+
+    fn day_high(&self) -> u8 {
+        let mut out = (self.days >> 8) as u8 & 0b1;
+        out.set_bit(6, self.halted);
+        out.set_bit(7, self.day_carry);
+        out
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x8) as usize]
+    }
+
+    /// Serialises the clock in the 48-byte format appended to save files by
+    /// other emulators: the live and latched registers as 32-bit values,
+    /// then a 64-bit UNIX timestamp. All values are little-endian.
+    fn save_footer(&mut self) -> Vec<u8> {
+        self.sync();
+        let timestamp = match self.clock {
+            RtcClock::Host => self.last_sync,
+            RtcClock::Emulated => SystemTime::now(),
+        };
+        let timestamp = timestamp.duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0)).as_secs();
+
+        let live = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            self.day_high(),
+        ];
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for &register in live.iter().chain(self.latched.iter()) {
+            footer.extend([register, 0, 0, 0].iter());
+        }
+        for i in 0..8 {
+            footer.push((timestamp >> (8 * i)) as u8);
+        }
+        footer
+    }
+
+    /// Restores the clock from a save file footer. Both the 48-byte format
+    /// and the older 44-byte format, with a 32-bit timestamp, are accepted.
+    fn load_footer(&mut self, footer: &[u8]) {
+        let word = |i: usize| footer[4 * i];
+        self.seconds = word(0) & 0x3F;
+        self.minutes = word(1) & 0x3F;
+        self.hours = word(2) & 0x1F;
+        self.days = (word(3) as u16) | ((word(4) as u16 & 0b1) << 8);
+        self.halted = word(4).get_bit(6);
+        self.day_carry = word(4).get_bit(7);
+        for i in 0..self.latched.len() {
+            self.latched[i] = word(5 + i);
+        }
+
+        let timestamp_len = if footer.len() >= RTC_FOOTER_SIZE { 8 } else { 4 };
+        let timestamp = footer[40..40 + timestamp_len].iter().rev()
+            .fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+        self.last_sync = UNIX_EPOCH + Duration::from_secs(timestamp);
+        self.cycles = 0;
+    }
+
+    fn write(&mut self, register: u8, val: u8) {
+        self.sync();
+        match register {
+            0x8 => {
+                self.seconds = val & 0x3F;
+                self.cycles = 0;
+            }
+            0x9 => self.minutes = val & 0x3F,
+            0xA => self.hours = val & 0x1F,
+            0xB => self.days = (self.days & 0x100) | val as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | ((val as u16 & 0b1) << 8);
+                self.halted = val.get_bit(6);
+                self.day_carry = val.get_bit(7);
+            }
+        }
+        self.latched[(register - 0x8) as usize] = match register {
+            0x8 => self.seconds,
+            0x9 => self.minutes,
+            0xA => self.hours,
+            0xB => self.days as u8,
+            _ => self.day_high(),
+        };
+    }
+}
+
+
+const RAM_SIZE_ADDR: usize = 0x0149;
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const MBC2_RAM_SIZE: usize = 512;
+
+/// The RTC counts seconds off a 32.768 kHz crystal; in emulated time, that
+/// is once every 2^20 M-cycles.
+const RTC_CYCLES_PER_SECOND: u32 = 1 << 20;
+const RTC_DAY_MASK: u16 = 0x1FF;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const RTC_FOOTER_SIZE: usize = 48;
+const RTC_FOOTER_MIN_SIZE: usize = 44;
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// Where each ROM bank stores its own number, as a little-endian word.
+    const BANK_MARK: u16 = 0x2000;
+
+    /// Builds a ROM of the given number of 16 KiB banks, each marked with
+    /// its bank number.
+    fn rom(cartridge_type: u8, banks: usize, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            let mark = bank * ROM_BANK_SIZE + BANK_MARK as usize;
+            rom[mark] = bank as u8;
+            rom[mark + 1] = (bank >> 8) as u8;
+        }
+        rom[0x0147] = cartridge_type;
+        rom[RAM_SIZE_ADDR] = ram_size;
+        rom
+    }
+
+    fn cartridge(cartridge_type: u8, banks: usize, ram_size: u8) -> Cartridge {
+        Cartridge::from_buffer(rom(cartridge_type, banks, ram_size)).unwrap()
+    }
+
+    /// The bank mapped at the given area, 0x0000 or 0x4000.
+    fn mapped_bank(cart: &Cartridge, area: u16) -> usize {
+        let addr = area + BANK_MARK;
+        cart.read8(addr) as usize | (cart.read8(addr + 1) as usize) << 8
+    }
+
+    #[test]
+    fn no_mbc() {
+        let mut cart = cartridge(0x08, 2, 0x02);
+        assert_eq!(mapped_bank(&cart, 0x0000), 0);
+        assert_eq!(mapped_bank(&cart, 0x4000), 1);
+        cart.write8(0x2000, 0x00);
+        assert_eq!(cart.read8(0x2000), 0);
+
+        cart.write_ram(0x1FFF, 0x42);
+        assert_eq!(cart.read_ram(0x1FFF), 0x42);
+
+        // Without RAM, reads are open bus.
+        let mut cart = cartridge(0x00, 2, 0x00);
+        cart.write_ram(0x0000, 0x42);
+        assert_eq!(cart.read_ram(0x0000), 0xFF);
+    }
+
+    #[test]
+    fn mbc1_bank_0_selects_bank_1() {
+        let mut cart = cartridge(0x01, 128, 0x00);
+        assert_eq!(mapped_bank(&cart, 0x4000), 1);
+        cart.write8(0x2000, 0x00);
+        assert_eq!(mapped_bank(&cart, 0x4000), 1);
+        // Only the lower five bits are checked, so banks 0x20, 0x40 and
+        // 0x60 can't be mapped either.
+        cart.write8(0x2000, 0xE0);
+        cart.write8(0x4000, 0x01);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x21);
+        cart.write8(0x2000, 0x1F);
+        cart.write8(0x4000, 0x03);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x7F);
+    }
+
+    #[test]
+    fn mbc1_mode_1_maps_upper_bits_at_0000() {
+        let mut cart = cartridge(0x01, 128, 0x00);
+        cart.write8(0x4000, 0x02);
+        cart.write8(0x2000, 0x05);
+        assert_eq!(mapped_bank(&cart, 0x0000), 0x00);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x45);
+
+        cart.write8(0x6000, 0x01);
+        assert_eq!(mapped_bank(&cart, 0x0000), 0x40);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x45);
+
+        cart.write8(0x6000, 0x00);
+        assert_eq!(mapped_bank(&cart, 0x0000), 0x00);
+    }
+
+    #[test]
+    fn mbc1_mode_1_banks_ram() {
+        let mut cart = cartridge(0x03, 4, 0x03);
+        cart.write8(0x0000, 0x0A);
+        cart.write8(0x4000, 0x02);
+        // In mode 0, the RAM bank register is ignored.
+        cart.write_ram(0x0000, 0x42);
+        cart.write8(0x6000, 0x01);
+        assert_eq!(cart.read_ram(0x0000), 0x00);
+        cart.write_ram(0x0000, 0x43);
+        cart.write8(0x6000, 0x00);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+        assert_eq!(cart.ram[2 * RAM_BANK_SIZE], 0x43);
+    }
+
+    #[test]
+    fn mbc1_multicart() {
+        // Four games of 16 banks, each starting with a logo.
+        let mut buffer = rom(0x01, 64, 0x00);
+        for game in 0..4 {
+            let start = game * 0x10 * ROM_BANK_SIZE;
+            for (i, byte) in buffer[start + 0x0104..start + 0x0134]
+                    .iter_mut().enumerate() {
+                *byte = i as u8 + 1;
+            }
+        }
+        let mut cart = Cartridge::from_buffer(buffer.clone()).unwrap();
+        cart.write8(0x4000, 0x01);
+        cart.write8(0x2000, 0x13);
+        // The upper bits start from bit 4, and bit 4 of the lower register
+        // isn't connected.
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x13);
+        cart.write8(0x2000, 0x03);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x13);
+        cart.write8(0x6000, 0x01);
+        assert_eq!(mapped_bank(&cart, 0x0000), 0x10);
+
+        // Without the second logo, it's a normal 1 MiB cartridge.
+        buffer[0x10 * ROM_BANK_SIZE + 0x0104] = 0x00;
+        let mut cart = Cartridge::from_buffer(buffer).unwrap();
+        cart.write8(0x4000, 0x01);
+        cart.write8(0x2000, 0x03);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x23);
+    }
+
+    #[test]
+    fn mbc1_ram_enable() {
+        let mut cart = cartridge(0x03, 4, 0x02);
+        cart.write_ram(0x0000, 0x42);
+        assert_eq!(cart.read_ram(0x0000), 0xFF);
+
+        // Only the lower nibble is checked.
+        cart.write8(0x1FFF, 0xFA);
+        assert_eq!(cart.read_ram(0x0000), 0x00);
+        cart.write_ram(0x0000, 0x42);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+
+        cart.write8(0x0000, 0x0B);
+        assert_eq!(cart.read_ram(0x0000), 0xFF);
+        cart.write8(0x0000, 0x0A);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+    }
+
+    #[test]
+    fn mbc1_bank_wraps_to_rom_size() {
+        // 64 KiB of ROM only decodes two bank bits.
+        let mut cart = cartridge(0x01, 4, 0x00);
+        cart.write8(0x2000, 0x06);
+        assert_eq!(mapped_bank(&cart, 0x4000), 2);
+        cart.write8(0x2000, 0x1F);
+        cart.write8(0x4000, 0x03);
+        assert_eq!(mapped_bank(&cart, 0x4000), 3);
+        cart.write8(0x6000, 0x01);
+        assert_eq!(mapped_bank(&cart, 0x0000), 0);
+
+        // So does 8 KiB of RAM.
+        let mut cart = cartridge(0x03, 4, 0x02);
+        cart.write8(0x0000, 0x0A);
+        cart.write_ram(0x0000, 0x42);
+        cart.write8(0x4000, 0x01);
+        cart.write8(0x6000, 0x01);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+    }
+
+    fn rtc_cartridge() -> Cartridge {
+        let mut cart = cartridge(0x10, 4, 0x03);
+        cart.write8(0x0000, 0x0A);
+        cart
+    }
+
+    fn rtc_register(cart: &mut Cartridge, register: u8) -> u8 {
+        cart.write8(0x4000, register);
+        cart.read_ram(0x0000)
+    }
+
+    fn set_rtc_register(cart: &mut Cartridge, register: u8, val: u8) {
+        cart.write8(0x4000, register);
+        cart.write_ram(0x0000, val);
+    }
+
+    fn latch(cart: &mut Cartridge) {
+        cart.write8(0x6000, 0x00);
+        cart.write8(0x6000, 0x01);
+    }
+
+    fn run_seconds(cart: &mut Cartridge, seconds: u32) {
+        for _ in 0..seconds * RTC_CYCLES_PER_SECOND / 128 {
+            cart.tick(128);
+        }
+    }
+
+    #[test]
+    fn rtc_latch() {
+        let mut cart = rtc_cartridge();
+        run_seconds(&mut cart, 3);
+        // The registers hold their values until latched.
+        assert_eq!(rtc_register(&mut cart, 0x08), 0);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x08), 3);
+
+        // Writing 0x01 only latches after 0x00.
+        run_seconds(&mut cart, 1);
+        cart.write8(0x6000, 0x01);
+        assert_eq!(rtc_register(&mut cart, 0x08), 3);
+        cart.write8(0x6000, 0x02);
+        cart.write8(0x6000, 0x01);
+        assert_eq!(rtc_register(&mut cart, 0x08), 3);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x08), 4);
+    }
+
+    #[test]
+    fn rtc_halt() {
+        let mut cart = rtc_cartridge();
+        set_rtc_register(&mut cart, 0x0C, 0x40);
+        run_seconds(&mut cart, 2);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x08), 0);
+        assert_eq!(rtc_register(&mut cart, 0x0C), 0x40);
+
+        set_rtc_register(&mut cart, 0x0C, 0x00);
+        run_seconds(&mut cart, 2);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x08), 2);
+    }
+
+    #[test]
+    fn rtc_counters_roll_over() {
+        let mut cart = rtc_cartridge();
+        set_rtc_register(&mut cart, 0x08, 59);
+        set_rtc_register(&mut cart, 0x09, 59);
+        set_rtc_register(&mut cart, 0x0A, 23);
+        set_rtc_register(&mut cart, 0x0B, 0xFF);
+        run_seconds(&mut cart, 1);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x08), 0);
+        assert_eq!(rtc_register(&mut cart, 0x09), 0);
+        assert_eq!(rtc_register(&mut cart, 0x0A), 0);
+        assert_eq!(rtc_register(&mut cart, 0x0B), 0x00);
+        assert_eq!(rtc_register(&mut cart, 0x0C), 0x01);
+    }
+
+    #[test]
+    fn rtc_day_carry() {
+        let mut cart = rtc_cartridge();
+        set_rtc_register(&mut cart, 0x08, 59);
+        set_rtc_register(&mut cart, 0x09, 59);
+        set_rtc_register(&mut cart, 0x0A, 23);
+        set_rtc_register(&mut cart, 0x0B, 0xFF);
+        set_rtc_register(&mut cart, 0x0C, 0x01);
+        run_seconds(&mut cart, 1);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x0B), 0x00);
+        assert_eq!(rtc_register(&mut cart, 0x0C), 0x80);
+
+        // The carry stays set until the game clears it.
+        run_seconds(&mut cart, 1);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x0C), 0x80);
+        set_rtc_register(&mut cart, 0x0C, 0x00);
+        latch(&mut cart);
+        assert_eq!(rtc_register(&mut cart, 0x0C), 0x00);
+    }
+
+    #[test]
+    fn rtc_footer_round_trip() {
+        let mut cart = rtc_cartridge();
+        set_rtc_register(&mut cart, 0x09, 12);
+        set_rtc_register(&mut cart, 0x0A, 5);
+        set_rtc_register(&mut cart, 0x0B, 0x34);
+        set_rtc_register(&mut cart, 0x0C, 0x81);
+        latch(&mut cart);
+        run_seconds(&mut cart, 1);
+        cart.write8(0x4000, 0x00);
+        cart.write_ram(0x0000, 0x42);
+
+        let data = cart.save_data();
+        assert_eq!(data.len(), 32 * 1024 + RTC_FOOTER_SIZE);
+        let footer = &data[32 * 1024..];
+        // The live registers, then the latched ones, as 32-bit words.
+        let words: Vec<_> = footer[..40].chunks(4).collect();
+        let live = [1, 12, 5, 0x34, 0x81];
+        let latched = [0, 12, 5, 0x34, 0x81];
+        for (word, &expected) in words.iter()
+                .zip(live.iter().chain(latched.iter())) {
+            assert_eq!(*word, [expected, 0, 0, 0]);
+        }
+
+        let mut loaded = rtc_cartridge();
+        loaded.load_save_data(&data);
+        assert_eq!(loaded.read_ram(0x0000), 0x42);
+        for (register, &expected) in (0x08..0x0D).zip(latched.iter()) {
+            assert_eq!(rtc_register(&mut loaded, register), expected);
+        }
+        latch(&mut loaded);
+        for (register, &expected) in (0x08..0x0D).zip(live.iter()) {
+            assert_eq!(rtc_register(&mut loaded, register), expected);
+        }
+        assert_eq!(loaded.save_data()[..40], data[..40]);
+    }
+
+    #[test]
+    fn mbc2_ram_is_4_bit() {
+        let mut cart = cartridge(0x06, 4, 0x00);
+        cart.write8(0x0000, 0x0A);
+        cart.write_ram(0x0000, 0x5A);
+        assert_eq!(cart.read_ram(0x0000), 0xFA);
+        // The 512 half-bytes repeat through the RAM area.
+        assert_eq!(cart.read_ram(0x0200), 0xFA);
+        assert_eq!(cart.read_ram(0x1E00), 0xFA);
+        cart.write_ram(0x1FFF, 0x03);
+        assert_eq!(cart.read_ram(0x01FF), 0xF3);
+    }
+
+    #[test]
+    fn mbc2_address_bit_8_selects_register() {
+        let mut cart = cartridge(0x06, 16, 0x00);
+        // With A8 clear, writes enable RAM instead of selecting a bank.
+        cart.write8(0x2000, 0x0A);
+        assert_eq!(mapped_bank(&cart, 0x4000), 1);
+        cart.write_ram(0x0000, 0x05);
+        assert_eq!(cart.read_ram(0x0000), 0xF5);
+
+        cart.write8(0x2100, 0x05);
+        assert_eq!(mapped_bank(&cart, 0x4000), 5);
+        cart.write8(0x0100, 0x0F);
+        assert_eq!(mapped_bank(&cart, 0x4000), 15);
+        cart.write8(0x3F00, 0x10);
+        assert_eq!(mapped_bank(&cart, 0x4000), 1);
+        assert_eq!(cart.read_ram(0x0000), 0xF5);
+
+        cart.write8(0x3E00, 0x00);
+        assert_eq!(cart.read_ram(0x0000), 0xFF);
+        // Writes above 0x3FFF do nothing.
+        cart.write8(0x4000, 0x0A);
+        cart.write8(0x4100, 0x02);
+        assert_eq!(cart.read_ram(0x0000), 0xFF);
+        assert_eq!(mapped_bank(&cart, 0x4000), 1);
+    }
+
+    #[test]
+    fn mbc5_9_bit_rom_bank() {
+        let mut cart = cartridge(0x19, 512, 0x00);
+        cart.write8(0x2000, 0xFF);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0xFF);
+        cart.write8(0x3000, 0xFF);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x1FF);
+        cart.write8(0x2000, 0x00);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0x100);
+        // Bank 0 can be mapped at 0x4000 too.
+        cart.write8(0x3000, 0x00);
+        assert_eq!(mapped_bank(&cart, 0x4000), 0);
+        assert_eq!(mapped_bank(&cart, 0x0000), 0);
+    }
+
+    #[test]
+    fn mbc5_ram_banks() {
+        let mut cart = cartridge(0x1B, 4, 0x04);
+        cart.write8(0x0000, 0x0A);
+        for bank in 0..16 {
+            cart.write8(0x4000, bank);
+            cart.write_ram(0x0000, bank);
+        }
+        cart.write8(0x4000, 0x0C);
+        assert_eq!(cart.read_ram(0x0000), 0x0C);
+        // Unlike MBC1, the whole value is checked.
+        cart.write8(0x0000, 0x1A);
+        assert_eq!(cart.read_ram(0x0000), 0xFF);
+    }
+
+    #[test]
+    fn mbc5_rumble() {
+        let mut cart = cartridge(0x1E, 4, 0x03);
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let recorded = changes.clone();
+        cart.set_rumble_callback(move |on| recorded.borrow_mut().push(on));
+
+        cart.write8(0x0000, 0x0A);
+        cart.write8(0x4000, 0x01);
+        cart.write_ram(0x0000, 0x42);
+        // Bit 3 drives the motor, and isn't a RAM bank bit.
+        cart.write8(0x4000, 0x09);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+        cart.write8(0x4000, 0x0B);
+        cart.write8(0x4000, 0x01);
+        assert_eq!(*changes.borrow(), [true, false]);
+
+        // Without rumble, bit 3 selects a RAM bank.
+        let mut cart = cartridge(0x1B, 4, 0x04);
+        cart.write8(0x0000, 0x0A);
+        cart.write8(0x4000, 0x01);
+        cart.write_ram(0x0000, 0x42);
+        cart.write8(0x4000, 0x09);
+        assert_eq!(cart.read_ram(0x0000), 0x00);
+    }
+
+    /// A directory of its own for a test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(
+                format!("gameboy-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn save_file_round_trip() {
+        let dir = TempDir::new("save-file-round-trip");
+        let rom_path = dir.0.join("game.gb");
+        fs::write(&rom_path, rom(0x03, 4, 0x02)).unwrap();
+
+        // Without a save file, RAM starts out cleared.
+        let mut cart = Cartridge::from_file(&rom_path).unwrap();
+        assert!(cart.has_battery());
+        cart.write8(0x0000, 0x0A);
+        assert_eq!(cart.read_ram(0x0000), 0x00);
+        cart.write_ram(0x0000, 0x42);
+        cart.write_ram(0x1FFF, 0x43);
+        cart.flush_save().unwrap();
+
+        let save = fs::read(dir.0.join("game.sav")).unwrap();
+        assert_eq!(save.len(), 8 * 1024);
+        assert_eq!(save, cart.save_data());
+
+        let mut cart = Cartridge::from_file(&rom_path).unwrap();
+        cart.write8(0x0000, 0x0A);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+        assert_eq!(cart.read_ram(0x1FFF), 0x43);
+    }
+
+    #[test]
+    fn save_path_and_load_save() {
+        let dir = TempDir::new("save-path-and-load-save");
+        let save_path = dir.0.join("other.sav");
+        let mut cart = cartridge(0x03, 4, 0x02);
+        cart.write8(0x0000, 0x0A);
+        cart.write_ram(0x0100, 0x42);
+        // Nothing is written until there is a save path.
+        cart.flush_save().unwrap();
+        assert!(!save_path.exists());
+        cart.set_save_path(&save_path);
+        cart.flush_save().unwrap();
+
+        let mut loaded = cartridge(0x03, 4, 0x02);
+        loaded.load_save(&save_path).unwrap();
+        loaded.write8(0x0000, 0x0A);
+        assert_eq!(loaded.read_ram(0x0100), 0x42);
+        assert!(loaded.load_save(dir.0.join("missing.sav")).is_err());
+    }
+
+    #[test]
+    fn flush_save_needs_battery() {
+        let dir = TempDir::new("flush-save-needs-battery");
+        let save_path = dir.0.join("game.sav");
+        let mut cart = cartridge(0x02, 4, 0x02);
+        assert!(!cart.has_battery());
+        cart.set_save_path(&save_path);
+        cart.flush_save().unwrap();
+        assert!(!save_path.exists());
+    }
+
+    #[test]
+    fn save_file_with_rtc_footer() {
+        let dir = TempDir::new("save-file-with-rtc-footer");
+        let rom_path = dir.0.join("game.gb");
+        fs::write(&rom_path, rom(0x10, 4, 0x03)).unwrap();
+
+        let mut cart = Cartridge::from_file(&rom_path).unwrap();
+        cart.write8(0x0000, 0x0A);
+        cart.write_ram(0x0000, 0x42);
+        set_rtc_register(&mut cart, 0x09, 30);
+        cart.flush_save().unwrap();
+        let save = fs::read(dir.0.join("game.sav")).unwrap();
+        assert_eq!(save.len(), 32 * 1024 + RTC_FOOTER_SIZE);
+
+        let mut cart = Cartridge::from_file(&rom_path).unwrap();
+        cart.write8(0x0000, 0x0A);
+        assert_eq!(rtc_register(&mut cart, 0x09), 30);
+        cart.write8(0x4000, 0x00);
+        assert_eq!(cart.read_ram(0x0000), 0x42);
+
+        // The older footer, with a 32-bit timestamp, loads too.
+        let mut cart = rtc_cartridge();
+        cart.load_save_data(&save[..32 * 1024 + RTC_FOOTER_MIN_SIZE]);
+        assert_eq!(rtc_register(&mut cart, 0x09), 30);
+    }
+
+    #[test]
+    fn save_file_of_wrong_size() {
+        let mut cart = cartridge(0x03, 4, 0x02);
+        cart.write8(0x0000, 0x0A);
+        cart.write_ram(0x1FFF, 0x42);
+
+        // A short save only fills the start of RAM.
+        cart.load_save_data(&[0x01, 0x02]);
+        assert_eq!(cart.read_ram(0x0000), 0x01);
+        assert_eq!(cart.read_ram(0x0001), 0x02);
+        assert_eq!(cart.read_ram(0x1FFF), 0x42);
+        assert_eq!(cart.save_data().len(), 8 * 1024);
+
+        // Anything past the end of RAM is ignored.
+        cart.load_save_data(&vec![0x07; 8 * 1024 + 100]);
+        assert_eq!(cart.read_ram(0x1FFF), 0x07);
+        assert_eq!(cart.save_data().len(), 8 * 1024);
+
+        // So is a footer too short to hold the clock.
+        let mut cart = rtc_cartridge();
+        set_rtc_register(&mut cart, 0x09, 30);
+        cart.load_save_data(&vec![0x07; 32 * 1024 + RTC_FOOTER_MIN_SIZE - 1]);
+        assert_eq!(rtc_register(&mut cart, 0x09), 30);
+        cart.write8(0x4000, 0x03);
+        assert_eq!(cart.read_ram(0x1FFF), 0x07);
+    }
+}
@@ -1,38 +1,116 @@
+use std::mem;
+
+use error::{Error, Result};
 use mmu::MMU;
 use cpu::instructions::{FlagState, Instruction, Src8, Dest8, Src16};
 use cpu::registers::{Flag, Reg8, Reg16, Registers};
 
 #[derive(Debug, Default)]
 pub struct Cpu {
-    regs: Registers
+    regs: Registers,
+    /// The interrupt master enable flag.
+    ime: bool,
+    /// EI enables interrupts only after the following instruction; this
+    /// counts down the instructions until IME is set.
+    ime_delay: u8,
+    state: State,
+    /// Set when HALT is executed with IME disabled and an interrupt already
+    /// pending. The next opcode byte is then read twice.
+    halt_bug: bool,
 }
 
 impl Cpu {
     pub fn new() -> Cpu {
         Cpu {
-            regs: Registers::new()
+            regs: Registers::new(),
+            ..Default::default()
         }
     }
 
-    pub fn tick(&mut self, mmu: &mut MMU) {
-        let mut pc = self.regs.read16(Reg16::PC);
+    /// Executes a single instruction, or dispatches a pending interrupt, and
+    /// returns the number of M-cycles it took.
+    ///
+    /// Executing an illegal opcode returns an error. As on hardware, the CPU
+    /// then stays locked up, and further ticks only let time pass.
+    pub fn tick(&mut self, mmu: &mut MMU) -> Result<u8> {
+        match self.state {
+            State::Halted if mmu.pending_interrupt().is_none() => return Ok(1),
+            State::Stopped if !Self::stop_released(mmu) => return Ok(1),
+            State::Locked => return Ok(1),
+            _ => self.state = State::Running,
+        }
+
+        if let Some(cycles) = self.handle_interrupts(mmu) {
+            return Ok(cycles);
+        }
+
+        let start_pc = self.regs.read16(Reg16::PC);
+        let mut pc = start_pc;
+        let mut halt_bug = mem::replace(&mut self.halt_bug, false);
         let instruction = Instruction::decode(|| {
             let word = mmu.read8(pc);
-            pc += 1;
+            if halt_bug {
+                halt_bug = false;
+            } else {
+                pc = pc.wrapping_add(1);
+            }
             word
         });
         self.regs.write16(Reg16::PC, pc);
-        println!("{:?}", instruction);
-        self.handle_instruction(mmu, instruction);
+        let branch_taken = self.handle_instruction(mmu, instruction);
+        if let Instruction::Unknown(opcode) = instruction {
+            return Err(Error::IllegalOpcode { opcode: opcode, pc: start_pc });
+        }
+
+        if self.ime_delay > 0 {
+            self.ime_delay -= 1;
+            if self.ime_delay == 0 {
+                self.ime = true;
+            }
+        }
+        Ok(instruction.cycles().get(branch_taken))
+    }
+
+    /// Returns whether the CPU is in STOP mode. The system clock, and with it
+    /// every other device, is halted while stopped.
+    pub fn stopped(&self) -> bool {
+        self.state == State::Stopped
+    }
+
+    /// Returns whether the CPU should leave STOP mode, which happens when a
+    /// button in a selected group is pressed.
+    fn stop_released(mmu: &MMU) -> bool {
+        mmu.joypad().input_lines() != 0b1111
+    }
+
+    /// Jumps to the vector of the highest priority pending interrupt, if
+    /// interrupts are enabled. Returns the cycles spent dispatching.
+    fn handle_interrupts(&mut self, mmu: &mut MMU) -> Option<u8> {
+        if !self.ime {
+            return None;
+        }
+        mmu.pending_interrupt().map(|interrupt| {
+            self.ime = false;
+            self.ime_delay = 0;
+            mmu.interrupts().acknowledge(interrupt);
+            let pc = self.regs.read16(Reg16::PC);
+            self.push16(mmu, pc);
+            self.regs.write16(Reg16::PC, interrupt.vector());
+            INTERRUPT_DISPATCH_CYCLES
+        })
     }
 
-    fn handle_instruction(&mut self, mmu: &mut MMU, instruction: Instruction) {
+    /// Executes an instruction. Returns whether a conditional branch was
+    /// taken.
+    fn handle_instruction(&mut self, mmu: &mut MMU, instruction: Instruction)
+            -> bool {
         use cpu::instructions::Instruction::*;
+        let mut branch_taken = false;
         match instruction {
             ComplementCarry => {
                 let c = self.regs.get_flag(Flag::C);
                 self.regs.set_flag(Flag::S, false);
-                self.regs.set_flag(Flag::H, c);
+                self.regs.set_flag(Flag::H, false);
                 self.regs.set_flag(Flag::C, !c);
             }
             SetCarry => {
@@ -41,6 +119,26 @@ impl Cpu {
                 self.regs.set_flag(Flag::C, true);
             }
             Nop => {},
+            Halt => {
+                if !self.ime && mmu.pending_interrupt().is_some() {
+                    self.halt_bug = true;
+                } else {
+                    self.state = State::Halted;
+                }
+            }
+            Stop => {
+                mmu.reset_divider();
+                self.state = State::Stopped;
+            }
+            DisableInterrupts => {
+                self.ime = false;
+                self.ime_delay = 0;
+            }
+            EnableInterrupts => {
+                if !self.ime && self.ime_delay == 0 {
+                    self.ime_delay = 2;
+                }
+            }
             Load8(dest, src) => {
                 let val = self.read_src8(mmu, src);
                 self.write_dest8(mmu, dest, val);
@@ -49,13 +147,13 @@ impl Cpu {
                 let val = self.read_src8(mmu, src);
                 self.write_dest8(mmu, dest, val);
                 let hl = self.regs.read16(Reg16::HL);
-                self.regs.write16(Reg16::HL, hl+1);
+                self.regs.write16(Reg16::HL, hl.wrapping_add(1));
             }
             Load8Dec(dest, src) => {
                 let val = self.read_src8(mmu, src);
                 self.write_dest8(mmu, dest, val);
                 let hl = self.regs.read16(Reg16::HL);
-                self.regs.write16(Reg16::HL, hl-1);
+                self.regs.write16(Reg16::HL, hl.wrapping_sub(1));
             }
             ReadIo(src) => {
                 let addr = match src {
@@ -76,29 +174,20 @@ impl Cpu {
                 mmu.write8(addr, val);
             }
             Load16(dest, src) => {
-                let val = match src {
-                    Src16::Imm(val) => val,
-                    Src16::Reg(reg) => self.regs.read16(reg),
-                    Src16::Offset(offset) => {
-                        let sp = self.regs.read16(Reg16::SP);
-                        self.regs.set_flag(Flag::H,
-                            (sp & 0xF) + (offset as u8 as u16) > 0xF);
-                        self.regs.set_flag(Flag::C,
-                            (sp & 0xFF) + (offset as u16) > 0xFF);
-                        ((sp as i16) + (offset as i16)) as u16
-                    }
-                };
+                let val = self.read_src16(src);
                 self.regs.write16(dest, val);
             }
+            StoreStackPointer(addr) => {
+                let sp = self.regs.read16(Reg16::SP);
+                mmu.write16(addr, sp);
+            }
             Push(reg) => {
-                let sp = self.regs.read16(Reg16::SP) - 2;
-                self.regs.write16(Reg16::SP, sp);
-                mmu.write16(sp, self.regs.read16(reg));
+                let val = self.regs.read16(reg);
+                self.push16(mmu, val);
             }
             Pop(reg) => {
-                let sp = self.regs.read16(Reg16::SP);
-                self.regs.write16(reg, mmu.read16(sp));
-                self.regs.write16(Reg16::SP, sp-2);
+                let val = self.pop16(mmu);
+                self.regs.write16(reg, val);
             }
             Add(src) => {
                 self.do_add(mmu, src, false);
@@ -120,9 +209,9 @@ impl Cpu {
                 let val = left & right;
                 self.regs.write8(Reg8::A, val);
                 self.regs.set_flag(Flag::Z, val == 0);
-                self.regs.set_flag(Flag::H, false);
+                self.regs.set_flag(Flag::H, true);
                 self.regs.set_flag(Flag::S, false);
-                self.regs.set_flag(Flag::C, true);
+                self.regs.set_flag(Flag::C, false);
             }
             Or(src) => {
                 let left = self.regs.read8(Reg8::A);
@@ -145,63 +234,47 @@ impl Cpu {
                 self.regs.set_flag(Flag::C, false);
             }
             Compare(src) => {
-                self.do_sub(mmu, src, false, true);
+                self.do_sub(mmu, src, false, false);
             }
-            Increment(Dest8::Reg(reg)) => {
-                let pre = self.regs.read8(reg);
-                let post = pre+1;
-                self.regs.write8(reg, post);
+            Increment(dest) => {
+                let pre = self.read_dest8(mmu, dest);
+                let post = pre.wrapping_add(1);
+                self.write_dest8(mmu, dest, post);
                 self.regs.set_flag(Flag::Z, post == 0);
                 self.regs.set_flag(Flag::S, false);
-                self.regs.set_flag(Flag::H, (pre & 0xF) + 1 > 0xF);
+                self.regs.set_flag(Flag::H, (pre & 0xF) == 0xF);
             }
-            Increment(Dest8::Indir(reg)) => {
-                let addr = self.regs.read16(reg);
-                let pre = mmu.read8(addr);
-                let post = pre+1;
-                mmu.write8(addr, post);
-                self.regs.set_flag(Flag::Z, post == 0);
-                self.regs.set_flag(Flag::S, false);
-                self.regs.set_flag(Flag::H, (pre & 0xF) + 1 > 0xF);
-            }
-            Decrement(Dest8::Reg(reg)) => {
-                let pre = self.regs.read8(reg);
-                let post = pre-1;
-                self.regs.write8(reg, post);
-                self.regs.set_flag(Flag::Z, post == 0);
-                self.regs.set_flag(Flag::S, true);
-                self.regs.set_flag(Flag::H, (pre & 0xF) + 1 > 0xF);
-            }
-            Decrement(Dest8::Indir(reg)) => {
-                let addr = self.regs.read16(reg);
-                let pre = mmu.read8(addr);
-                let post = pre-1;
-                mmu.write8(addr, post);
+            Decrement(dest) => {
+                let pre = self.read_dest8(mmu, dest);
+                let post = pre.wrapping_sub(1);
+                self.write_dest8(mmu, dest, post);
                 self.regs.set_flag(Flag::Z, post == 0);
                 self.regs.set_flag(Flag::S, true);
-                self.regs.set_flag(Flag::H, (pre & 0xF) < 1);
+                self.regs.set_flag(Flag::H, (pre & 0xF) == 0);
             }
             DecimalAdjust => {
                 let mut a = self.regs.read8(Reg8::A);
+                let mut carry = self.regs.get_flag(Flag::C);
                 if self.regs.get_flag(Flag::S) {
                     if self.regs.get_flag(Flag::H) {
-                        a -= 0x06;
+                        a = a.wrapping_sub(0x06);
                     }
-                    if self.regs.get_flag(Flag::C) {
-                        a -= 0x60;
+                    if carry {
+                        a = a.wrapping_sub(0x60);
                     }
                 } else {
-                    if (a & 0x0F) > 0x09 || self.regs.get_flag(Flag::H) {
-                        a += 0x06;
+                    if carry || a > 0x99 {
+                        a = a.wrapping_add(0x60);
+                        carry = true;
                     }
-                    if a > 0x90 || self.regs.get_flag(Flag::C) {
-                        a += 0x60;
-                        self.regs.set_flag(Flag::C, true);
+                    if (a & 0x0F) > 0x09 || self.regs.get_flag(Flag::H) {
+                        a = a.wrapping_add(0x06);
                     }
                 }
                 self.regs.write8(Reg8::A, a);
                 self.regs.set_flag(Flag::Z, a == 0);
                 self.regs.set_flag(Flag::H, false);
+                self.regs.set_flag(Flag::C, carry);
             }
             Complement => {
                 let val = !self.regs.read8(Reg8::A);
@@ -212,34 +285,31 @@ impl Cpu {
             Add16(reg, Src16::Reg(src)) => {
                 let left = self.regs.read16(reg);
                 let right = self.regs.read16(src);
-                let val = left + right;
+                let val = left.wrapping_add(right);
                 self.regs.write16(reg, val);
+                self.regs.set_flag(Flag::S, false);
                 self.regs.set_flag(Flag::H,
                     (left & 0xFFF) + (right & 0xFFF) > 0xFFF);
                 self.regs.set_flag(Flag::C,
                     (left as u32) + (right as u32) > 0xFFFF);
             }
-            Add16(Reg16::SP, Src16::Offset(offset)) => {
-                let sp = self.regs.read16(Reg16::SP);
-                let val = ((sp as i16) + (offset as i16)) as u16;
-                self.regs.write16(Reg16::SP, val);
-                self.regs.set_flag(Flag::H,
-                    (sp & 0xF) + (offset as u8 as u16) > 0xF);
-                self.regs.set_flag(Flag::C,
-                    (sp & 0xFF) + (offset as u16) > 0xFF);
+            Add16(reg, src) => {
+                let val = self.read_src16(src);
+                self.regs.write16(reg, val);
             }
             Increment16(reg) => {
                 let val = self.regs.read16(reg);
-                self.regs.write16(reg, val+1);
+                self.regs.write16(reg, val.wrapping_add(1));
             }
             Decrement16(reg) => {
                 let val = self.regs.read16(reg);
-                self.regs.write16(reg, val-1);
+                self.regs.write16(reg, val.wrapping_sub(1));
             }
             RotateLeftA => {
                 let val = self.regs.read8(Reg8::A);
                 let top = val >> 7;
                 self.regs.write8(Reg8::A, val<<1 | top);
+                self.regs.set_flag(Flag::Z, false);
                 self.regs.set_flag(Flag::S, false);
                 self.regs.set_flag(Flag::H, false);
                 self.regs.set_flag(Flag::C, top == 0b1);
@@ -249,6 +319,7 @@ impl Cpu {
                 let top = val >> 7;
                 let carry = if self.regs.get_flag(Flag::C) { 1 } else { 0 };
                 self.regs.write8(Reg8::A, val<<1 | carry);
+                self.regs.set_flag(Flag::Z, false);
                 self.regs.set_flag(Flag::S, false);
                 self.regs.set_flag(Flag::H, false);
                 self.regs.set_flag(Flag::C, top == 0b1);
@@ -257,6 +328,7 @@ impl Cpu {
                 let val = self.regs.read8(Reg8::A);
                 let bottom = val & 0b1;
                 self.regs.write8(Reg8::A, val>>1 | bottom<<7);
+                self.regs.set_flag(Flag::Z, false);
                 self.regs.set_flag(Flag::S, false);
                 self.regs.set_flag(Flag::H, false);
                 self.regs.set_flag(Flag::C, bottom == 0b1);
@@ -266,6 +338,7 @@ impl Cpu {
                 let bottom = val & 0b1;
                 let carry = if self.regs.get_flag(Flag::C) { 1 } else { 0 };
                 self.regs.write8(Reg8::A, val>>1 | carry<<7);
+                self.regs.set_flag(Flag::Z, false);
                 self.regs.set_flag(Flag::S, false);
                 self.regs.set_flag(Flag::H, false);
                 self.regs.set_flag(Flag::C, bottom == 0b1);
@@ -374,27 +447,27 @@ impl Cpu {
                 self.regs.write16(Reg16::PC, addr);
             }
             JumpConditional(flag, src) => {
-                if self.check_flag_state(flag) {
+                branch_taken = self.check_flag_state(flag);
+                if branch_taken {
                     let addr = self.read_src16(src);
                     self.regs.write16(Reg16::PC, addr);
                 }
             }
             RelativeJump(offset) => {
-                let addr = (self.regs.read16(Reg16::PC) as i16) + (offset as i16);
-                self.regs.write16(Reg16::PC, addr as u16);
+                self.do_relative_jump(offset);
             }
             RelativeJumpConditional(flag, offset) => {
-                if self.check_flag_state(flag) {
-                    let addr = (self.regs.read16(Reg16::PC) as i16) +
-                        (offset as i16);
-                    self.regs.write16(Reg16::PC, addr as u16);
+                branch_taken = self.check_flag_state(flag);
+                if branch_taken {
+                    self.do_relative_jump(offset);
                 }
             }
             Call(addr) => {
                 self.do_call(mmu, addr);
             }
             CallConditional(flag, addr) => {
-                if self.check_flag_state(flag) {
+                branch_taken = self.check_flag_state(flag);
+                if branch_taken {
                     self.do_call(mmu, addr);
                 }
             }
@@ -402,14 +475,24 @@ impl Cpu {
                 self.do_return(mmu);
             }
             ReturnConditional(flag) => {
-                if self.check_flag_state(flag) {
+                branch_taken = self.check_flag_state(flag);
+                if branch_taken {
                     self.do_return(mmu);
                 }
             }
-            Unknown(opcode, bitcode) =>
-                panic!("Got unknown opcode: 0x{:x}_{:x}", opcode, bitcode),
-            _ => panic!("Unimplemented instruction: {:?}", instruction),
+            ReturnEnableInterrupts => {
+                self.do_return(mmu);
+                self.ime = true;
+            }
+            Reset(addr) => {
+                self.do_call(mmu, addr);
+            }
+            Unknown(_) => {
+                // Illegal opcodes hang the CPU until it is reset.
+                self.state = State::Locked;
+            }
         }
+        branch_taken
     }
 
     fn read_src8(&self, mmu: &mut MMU, src: Src8) -> u8 {
@@ -421,7 +504,7 @@ impl Cpu {
         }
     }
 
-    fn read_dest8(&self, mmu: &MMU, dest: Dest8) -> u8 {
+    fn read_dest8(&self, mmu: &mut MMU, dest: Dest8) -> u8 {
         match dest {
             Dest8::Reg(reg) => self.regs.read8(reg),
             Dest8::Indir(reg) => mmu.read8(self.regs.read16(reg)),
@@ -437,11 +520,21 @@ impl Cpu {
         }
     }
 
-    fn read_src16(&self, src: Src16) -> u16 {
+    /// Reads a 16-bit operand. `Src16::Offset` adds a signed offset to SP,
+    /// which also sets the flags.
+    fn read_src16(&mut self, src: Src16) -> u16 {
         match src {
             Src16::Imm(val) => val,
             Src16::Reg(reg) => self.regs.read16(reg),
-            Src16::Offset(_) => unimplemented!(),
+            Src16::Offset(offset) => {
+                let sp = self.regs.read16(Reg16::SP);
+                let right = offset as u8 as u16;
+                self.regs.set_flag(Flag::Z, false);
+                self.regs.set_flag(Flag::S, false);
+                self.regs.set_flag(Flag::H, (sp & 0xF) + (right & 0xF) > 0xF);
+                self.regs.set_flag(Flag::C, (sp & 0xFF) + right > 0xFF);
+                sp.wrapping_add(offset as i16 as u16)
+            }
         }
     }
 
@@ -449,7 +542,7 @@ impl Cpu {
         let left = self.regs.read8(Reg8::A);
         let right = self.read_src8(mmu, src);
         let carry = if carry { 1 } else { 0 };
-        let val = left + right + carry;
+        let val = left.wrapping_add(right).wrapping_add(carry);
         self.regs.write8(Reg8::A, val);
         self.regs.set_flag(Flag::Z, val == 0);
         self.regs.set_flag(Flag::S, false);
@@ -463,30 +556,157 @@ impl Cpu {
         let left = self.regs.read8(Reg8::A);
         let right = self.read_src8(mmu, src);
         let carry = if carry { 1 } else { 0 };
-        let val = left - right - carry;
+        let val = left.wrapping_sub(right).wrapping_sub(carry);
         if store { self.regs.write8(Reg8::A, val); }
         self.regs.set_flag(Flag::Z, val == 0);
         self.regs.set_flag(Flag::S, true);
         self.regs.set_flag(Flag::H, (left & 0xF) < (right & 0xF) + carry);
-        self.regs.set_flag(Flag::C, left < right + carry);
+        self.regs.set_flag(Flag::C,
+            (left as u16) < (right as u16) + (carry as u16));
     }
 
     fn check_flag_state(&self, state: FlagState) -> bool {
         self.regs.get_flag(state.flag) == state.state
     }
 
+    fn do_relative_jump(&mut self, offset: i8) {
+        let pc = self.regs.read16(Reg16::PC);
+        self.regs.write16(Reg16::PC, pc.wrapping_add(offset as i16 as u16));
+    }
+
     fn do_call(&mut self, mmu: &mut MMU, addr: u16) {
         let pc = self.regs.read16(Reg16::PC);
-        let sp = self.regs.read16(Reg16::SP);
-        mmu.write16(sp, pc);
+        self.push16(mmu, pc);
         self.regs.write16(Reg16::PC, addr);
-        self.regs.write16(Reg16::SP, sp-2);
     }
 
     fn do_return(&mut self, mmu: &mut MMU) {
-        let sp = self.regs.read16(Reg16::SP);
-        let pc = mmu.read16(sp);
+        let pc = self.pop16(mmu);
         self.regs.write16(Reg16::PC, pc);
-        self.regs.write16(Reg16::SP, sp+2);
+    }
+
+    fn push16(&mut self, mmu: &mut MMU, val: u16) {
+        let sp = self.regs.read16(Reg16::SP).wrapping_sub(2);
+        self.regs.write16(Reg16::SP, sp);
+        mmu.write16(sp, val);
+    }
+
+    fn pop16(&mut self, mmu: &mut MMU) -> u16 {
+        let sp = self.regs.read16(Reg16::SP);
+        self.regs.write16(Reg16::SP, sp.wrapping_add(2));
+        mmu.read16(sp)
+    }
+}
+
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Running,
+    /// Waiting for an interrupt to become pending.
+    Halted,
+    /// Waiting for joypad input, with the system clock stopped.
+    Stopped,
+    /// Hung after executing an illegal opcode.
+    Locked,
+}
+
+impl Default for State {
+    fn default() -> Self { State::Running }
+}
+
+
+const INTERRUPT_DISPATCH_CYCLES: u8 = 5;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge::Cartridge;
+    use mmu::BOOTROM_DISABLE;
+
+    /// Maps `program` at the start of ROM and unmaps the boot ROM, which
+    /// takes the first M-cycle.
+    fn setup(program: &[u8]) -> (Cpu, MMU) {
+        let mut rom = vec![0; 0x8000];
+        rom[..program.len()].copy_from_slice(program);
+        let mut mmu = MMU::new(Cartridge::from_buffer(rom).unwrap());
+        mmu.write8(BOOTROM_DISABLE, 1);
+        mmu.finish_instruction(1);
+        (Cpu::new(), mmu)
+    }
+
+    /// Runs instructions the way `Gameboy::tick` does.
+    fn run(cpu: &mut Cpu, mmu: &mut MMU, instructions: usize) {
+        for _ in 0..instructions {
+            let cycles = cpu.tick(mmu).unwrap();
+            mmu.finish_instruction(cycles);
+        }
+    }
+
+    /// Reads DIV with `LDH A,(0x04)` after the given number of NOPs. DIV
+    /// increments every 64 M-cycles.
+    fn div_after_nops(nops: usize) -> u8 {
+        let mut program = vec![0x00; nops];
+        program.extend_from_slice(&[0xF0, 0x04]);
+        let (mut cpu, mut mmu) = setup(&program);
+        run(&mut cpu, &mut mmu, nops + 1);
+        cpu.regs.read8(Reg8::A)
+    }
+
+    #[test]
+    fn reads_happen_on_their_own_cycle() {
+        // The read is the third cycle of LDH, so it happens on the 63rd and
+        // 64th cycle.
+        assert_eq!(div_after_nops(59), 0);
+        assert_eq!(div_after_nops(60), 1);
+    }
+
+    #[test]
+    fn writes_happen_on_their_own_cycle() {
+        // LDH (0x04),A resets DIV on its third cycle, then JR loops in place.
+        let (mut cpu, mut mmu) = setup(&[0xE0, 0x04, 0x18, 0xFE]);
+        run(&mut cpu, &mut mmu, 1 + 20);
+        // 60 cycles of JR, and the read takes one more.
+        assert_eq!(mmu.read8(0xFF04), 0);
+
+        let (mut cpu, mut mmu) = setup(&[0xE0, 0x04, 0x18, 0xFE]);
+        run(&mut cpu, &mut mmu, 1 + 21);
+        assert_eq!(mmu.read8(0xFF04), 1);
+    }
+
+    #[test]
+    fn internal_cycles_are_not_counted_twice() {
+        // A taken JR takes three cycles, of which only the first two access
+        // the bus.
+        let (mut cpu, mut mmu) = setup(&[0x18, 0xFE]);
+        run(&mut cpu, &mut mmu, 20);
+        assert_eq!(mmu.read8(0xFF04), 0);
+
+        let (mut cpu, mut mmu) = setup(&[0x18, 0xFE]);
+        run(&mut cpu, &mut mmu, 21);
+        assert_eq!(mmu.read8(0xFF04), 1);
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_cpu() {
+        let illegal = (0..0x100).map(|op| op as u8).filter(|&op| {
+            match Instruction::decode(|| op) {
+                Instruction::Unknown(_) => true,
+                _ => false,
+            }
+        });
+        for opcode in illegal {
+            let (mut cpu, mut mmu) = setup(&[opcode]);
+            match cpu.tick(&mut mmu) {
+                Err(Error::IllegalOpcode { opcode: op, pc: 0 }) =>
+                    assert_eq!(op, opcode),
+                result => panic!("opcode {:#04X}: {:?}", opcode, result),
+            }
+            // The CPU hangs without executing anything else.
+            for _ in 0..16 {
+                assert_eq!(cpu.tick(&mut mmu).unwrap(), 1);
+            }
+        }
     }
 }
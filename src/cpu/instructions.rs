@@ -29,6 +29,11 @@ fn dest_reg8(opcode: u8) -> Dest8 {
     Dest8::Reg(byte_to_reg8(opcode>>3 & 0b111))
 }
 
+/// CB-prefixed instructions encode their operand in the lowest three bits.
+fn dest_cb_reg8(bitcode: u8) -> Dest8 {
+    Dest8::Reg(byte_to_reg8(bitcode & 0b111))
+}
+
 fn dest_mem(lower: u8, upper: u8) -> Dest8 {
     Dest8::Mem(u16_val(lower, upper))
 }
@@ -46,6 +51,25 @@ fn src16_imm(lower: u8, upper: u8) -> Src16 {
 }
 
 
+/// The number of machine cycles (M-cycles) an instruction takes to execute.
+/// One M-cycle is four clock cycles of the 4.19 MHz system clock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cycles {
+    Fixed(u8),
+    Branch { taken: u8, not_taken: u8 },
+}
+
+impl Cycles {
+    pub fn get(&self, branch_taken: bool) -> u8 {
+        match *self {
+            Cycles::Fixed(cycles) => cycles,
+            Cycles::Branch { taken, .. } if branch_taken => taken,
+            Cycles::Branch { not_taken, .. } => not_taken,
+        }
+    }
+}
+
+
 #[derive(Copy, Clone, Debug)]
 pub struct FlagState {
     pub flag: Flag,
@@ -77,6 +101,7 @@ pub enum Instruction {
     Load8Inc(Dest8, Src8),
     Load8Dec(Dest8, Src8),
     Load16(Reg16, Src16),
+    StoreStackPointer(u16),
     ReadIo(Src8),
     WriteIo(Dest8),
     Push(Reg16),
@@ -128,7 +153,7 @@ pub enum Instruction {
     ReturnEnableInterrupts,
     Reset(u16),
 
-    Unknown(u8, u8),
+    Unknown(u8),
 }
 
 impl Instruction {
@@ -143,10 +168,11 @@ impl Instruction {
             (0,0,1,1,0,1,1,1) => SetCarry,
             (0,0,0,0,0,0,0,0) => Nop,
             (0,1,1,1,0,1,1,0) => Halt,
-            (0,0,0,1,0,0,0,0) => match read_word() {
-                0 => Stop,
-                byte => Unknown(opcode, byte),
-            },
+            (0,0,0,1,0,0,0,0) => {
+                // STOP is followed by a padding byte, which is ignored.
+                read_word();
+                Stop
+            }
             (1,1,1,1,0,0,1,1) => DisableInterrupts,
             (1,1,1,1,1,0,1,1) => EnableInterrupts,
 
@@ -174,6 +200,8 @@ impl Instruction {
             (0,1,_,_,_,_,_,_) => Load8(dest_reg8(opcode), src_reg8(opcode)),
 
             (1,1,1,1,1,0,0,1) => Load16(Reg16::SP, Src16::Reg(HL)),
+            (0,0,0,0,1,0,0,0) =>
+                StoreStackPointer(u16_val(read_word(), read_word())),
             (0,0,_,_,0,0,0,1) =>
                 Load16(reg16(opcode, SP), src16_imm(read_word(), read_word())),
             (1,1,1,1,1,0,0,0) =>
@@ -226,39 +254,36 @@ impl Instruction {
                 let bitcode = read_word();
                 match bits(bitcode) {
                     (0,0,0,0,0,1,1,0) => RotateLeft(Dest8::Indir(HL)),
-                    (0,0,0,0,0,_,_,_) => RotateLeft(dest_reg8(bitcode)),
+                    (0,0,0,0,0,_,_,_) => RotateLeft(dest_cb_reg8(bitcode)),
                     (0,0,0,1,0,1,1,0) => RotateLeftCarry(Dest8::Indir(HL)),
-                    (0,0,0,1,0,_,_,_) => RotateLeftCarry(dest_reg8(bitcode)),
+                    (0,0,0,1,0,_,_,_) => RotateLeftCarry(dest_cb_reg8(bitcode)),
                     (0,0,0,0,1,1,1,0) => RotateRight(Dest8::Indir(HL)),
-                    (0,0,0,0,1,_,_,_) => RotateRight(dest_reg8(bitcode)),
+                    (0,0,0,0,1,_,_,_) => RotateRight(dest_cb_reg8(bitcode)),
                     (0,0,0,1,1,1,1,0) => RotateRightCarry(Dest8::Indir(HL)),
-                    (0,0,0,1,1,_,_,_) => RotateRightCarry(dest_reg8(bitcode)),
+                    (0,0,0,1,1,_,_,_) => RotateRightCarry(dest_cb_reg8(bitcode)),
                     (0,0,1,0,0,1,1,0) => ShiftLeft(Dest8::Indir(HL)),
-                    (0,0,1,0,0,_,_,_) => ShiftLeft(dest_reg8(bitcode)),
+                    (0,0,1,0,0,_,_,_) => ShiftLeft(dest_cb_reg8(bitcode)),
                     (0,0,1,0,1,1,1,0) => ShiftRightArithmetic(Dest8::Indir(HL)),
-                    (0,0,1,0,1,_,_,_) => ShiftRightArithmetic(dest_reg8(bitcode)),
+                    (0,0,1,0,1,_,_,_) => ShiftRightArithmetic(dest_cb_reg8(bitcode)),
                     (0,0,1,1,1,1,1,0) => ShiftRightLogical(Dest8::Indir(HL)),
-                    (0,0,1,1,1,_,_,_) => ShiftRightLogical(dest_reg8(bitcode)),
+                    (0,0,1,1,1,_,_,_) => ShiftRightLogical(dest_cb_reg8(bitcode)),
                     (0,0,1,1,0,1,1,0) => Swap(Dest8::Indir(HL)),
-                    (0,0,1,1,0,_,_,_) => Swap(dest_reg8(bitcode)),
+                    (0,0,1,1,0,_,_,_) => Swap(dest_cb_reg8(bitcode)),
 
                     (0,1,_,_,_,1,1,0) =>
                         TestBit(bitcode>>3 & 0b111, Dest8::Indir(HL)),
                     (0,1,_,_,_,_,_,_) =>
-                        TestBit(bitcode>>3 & 0b111,
-                                Dest8::Reg(byte_to_reg8(bitcode & 0b111))),
+                        TestBit(bitcode>>3 & 0b111, dest_cb_reg8(bitcode)),
                     (1,1,_,_,_,1,1,0) =>
                         SetBit(bitcode>>3 & 0b111, Dest8::Indir(HL)),
                     (1,1,_,_,_,_,_,_) =>
-                        SetBit(bitcode>>3 & 0b111,
-                               Dest8::Reg(byte_to_reg8(bitcode & 0b111))),
+                        SetBit(bitcode>>3 & 0b111, dest_cb_reg8(bitcode)),
                     (1,0,_,_,_,1,1,0) =>
                         ResetBit(bitcode>>3 & 0b111, Dest8::Indir(HL)),
                     (1,0,_,_,_,_,_,_) =>
-                        ResetBit(bitcode>>3 & 0b111,
-                                 Dest8::Reg(byte_to_reg8(bitcode & 0b111))),
+                        ResetBit(bitcode>>3 & 0b111, dest_cb_reg8(bitcode)),
 
-                    _ => Unknown(opcode, bitcode),
+                    _ => Unknown(opcode),
                 }
             }
 
@@ -279,11 +304,81 @@ impl Instruction {
             (1,1,0,1,1,0,0,1) => ReturnEnableInterrupts,
             (1,1,_,_,_,1,1,1) => Reset(8*(opcode>>3 & 0b111) as u16),
 
-            _ => Unknown(opcode, 0),
+            _ => Unknown(opcode),
+        }
+    }
+
+    pub fn cycles(&self) -> Cycles {
+        use self::Instruction::*;
+        use self::Cycles::*;
+
+        match *self {
+            ComplementCarry | SetCarry | Nop | Halt | Stop |
+                DisableInterrupts | EnableInterrupts => Fixed(1),
+
+            Load8(dest, src) => Fixed(1 + dest8_cost(dest) + src8_cost(src)),
+            Load8Inc(_, _) | Load8Dec(_, _) => Fixed(2),
+            Load16(_, Src16::Reg(_)) => Fixed(2),
+            Load16(_, _) => Fixed(3),
+            StoreStackPointer(_) => Fixed(5),
+            ReadIo(Src8::Mem(_)) | WriteIo(Dest8::Mem(_)) => Fixed(3),
+            ReadIo(_) | WriteIo(_) => Fixed(2),
+            Push(_) => Fixed(4),
+            Pop(_) => Fixed(3),
+
+            Add(src) | AddCarry(src) | Sub(src) | SubCarry(src) |
+                And(src) | Or(src) | Xor(src) | Compare(src) =>
+                Fixed(1 + src8_cost(src)),
+            Increment(dest) | Decrement(dest) =>
+                Fixed(1 + 2*dest8_cost(dest)),
+            DecimalAdjust | Complement => Fixed(1),
+
+            Add16(_, Src16::Offset(_)) => Fixed(4),
+            Add16(_, _) | Increment16(_) | Decrement16(_) => Fixed(2),
+
+            RotateLeftA | RotateLeftACarry | RotateRightA |
+                RotateRightACarry => Fixed(1),
+            RotateLeft(dest) | RotateLeftCarry(dest) | RotateRight(dest) |
+                RotateRightCarry(dest) | ShiftLeft(dest) |
+                ShiftRightLogical(dest) | ShiftRightArithmetic(dest) |
+                Swap(dest) | SetBit(_, dest) | ResetBit(_, dest) =>
+                Fixed(2 + 2*dest8_cost(dest)),
+            TestBit(_, dest) => Fixed(2 + dest8_cost(dest)),
+
+            Jump(Src16::Reg(_)) => Fixed(1),
+            Jump(_) => Fixed(4),
+            JumpConditional(_, _) => Branch { taken: 4, not_taken: 3 },
+            RelativeJump(_) => Fixed(3),
+            RelativeJumpConditional(_, _) => Branch { taken: 3, not_taken: 2 },
+            Call(_) => Fixed(6),
+            CallConditional(_, _) => Branch { taken: 6, not_taken: 3 },
+            Return | ReturnEnableInterrupts => Fixed(4),
+            ReturnConditional(_) => Branch { taken: 5, not_taken: 2 },
+            Reset(_) => Fixed(4),
+
+            Unknown(_) => Fixed(1),
         }
     }
 }
 
+/// Extra M-cycles spent fetching an 8-bit operand, beyond the opcode fetch.
+fn src8_cost(src: Src8) -> u8 {
+    match src {
+        Src8::Reg(_) => 0,
+        Src8::Imm(_) | Src8::Indir(_) => 1,
+        Src8::Mem(_) => 3,
+    }
+}
+
+/// Extra M-cycles spent storing an 8-bit operand.
+fn dest8_cost(dest: Dest8) -> u8 {
+    match dest {
+        Dest8::Reg(_) => 0,
+        Dest8::Indir(_) => 1,
+        Dest8::Mem(_) => 3,
+    }
+}
+
 fn bits(n: u8) -> (u8,u8,u8,u8,u8,u8,u8,u8) {
     (n >> 7 & 1,
      n >> 6 & 1,
@@ -325,3 +420,100 @@ fn byte_to_reg16(b: u8, high_reg: Reg16) -> Reg16 {
 fn u16_val(lower: u8, upper: u8) -> u16 {
     (lower as u16) + ((upper as u16) << 8)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes an instruction from its opcode bytes, padded with zeros.
+    fn decode(bytes: &[u8]) -> Instruction {
+        let mut bytes = bytes.iter().cloned();
+        Instruction::decode(|| bytes.next().unwrap_or(0))
+    }
+
+    #[test]
+    fn base_cycles() {
+        for opcode in 0..0x100 {
+            let opcode = opcode as u8;
+            if opcode == 0xCB || ILLEGAL_OPCODES.contains(&opcode) {
+                continue;
+            }
+            let cycles = decode(&[opcode]).cycles();
+            let not_taken = BASE_CYCLES[opcode as usize];
+            let taken = match TAKEN_CYCLES.iter()
+                    .find(|&&(op, _)| op == opcode) {
+                Some(&(_, taken)) => taken,
+                None => not_taken,
+            };
+            assert_eq!(cycles.get(false), not_taken,
+                       "opcode {:#04X} not taken", opcode);
+            assert_eq!(cycles.get(true), taken,
+                       "opcode {:#04X} taken", opcode);
+        }
+    }
+
+    #[test]
+    fn cb_cycles() {
+        for bitcode in 0..0x100 {
+            let bitcode = bitcode as u8;
+            let expected = match (bitcode & 0b111, bitcode >> 6) {
+                (0b110, 0b01) => 3,
+                (0b110, _) => 4,
+                _ => 2,
+            };
+            let cycles = decode(&[0xCB, bitcode]).cycles();
+            assert_eq!(cycles, Cycles::Fixed(expected),
+                       "opcode 0xCB {:#04X}", bitcode);
+        }
+    }
+
+    #[test]
+    fn illegal_opcodes() {
+        for opcode in 0..0x100 {
+            let opcode = opcode as u8;
+            let unknown = match decode(&[opcode]) {
+                Instruction::Unknown(op) => {
+                    assert_eq!(op, opcode);
+                    true
+                }
+                _ => false,
+            };
+            assert_eq!(unknown, ILLEGAL_OPCODES.contains(&opcode),
+                       "opcode {:#04X}", opcode);
+        }
+    }
+
+    const ILLEGAL_OPCODES: [u8; 11] = [
+        0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+    ];
+
+    /// The M-cycles of each base opcode, with any branch not taken. Illegal
+    /// opcodes and the 0xCB prefix are listed as 0.
+    const BASE_CYCLES: [u8; 0x100] = [
+        1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+        1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+        2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+        3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+        3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+    ];
+
+    /// The M-cycles of the conditional opcodes when the branch is taken.
+    const TAKEN_CYCLES: [(u8, u8); 16] = [
+        (0x20, 3), (0x28, 3), (0x30, 3), (0x38, 3),
+        (0xC0, 5), (0xC8, 5), (0xD0, 5), (0xD8, 5),
+        (0xC2, 4), (0xCA, 4), (0xD2, 4), (0xDA, 4),
+        (0xC4, 6), (0xCC, 6), (0xD4, 6), (0xDC, 6),
+    ];
+}
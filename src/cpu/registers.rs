@@ -64,7 +64,8 @@ impl Registers {
         match reg {
             Reg16::AF => {
                 self.a = (val >> 8) as u8;
-                self.f = (val & 0xFF) as u8;
+                // The lower nibble of F is not writable and always reads 0.
+                self.f = (val & 0xF0) as u8;
             }
             Reg16::BC => {
                 self.b = (val >> 8) as u8;
@@ -0,0 +1,94 @@
+/// The OAM DMA engine. Writing a page number to 0xFF46 copies 160 bytes from
+/// that page to OAM, one byte per M-cycle.
+#[derive(Debug, Default)]
+pub struct OamDma {
+    /// The value last written to 0xFF46.
+    register: u8,
+    /// A transfer waiting out its startup delay. A running transfer keeps
+    /// going until this one takes over.
+    pending: Option<Pending>,
+    active: Option<Transfer>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Pending {
+    source: u16,
+    delay: u8,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Transfer {
+    source: u16,
+    offset: u16,
+}
+
+impl OamDma {
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    pub fn start(&mut self, val: u8) {
+        self.register = val;
+        self.pending = Some(Pending {
+            source: (val as u16) << 8,
+            delay: START_DELAY,
+        });
+    }
+
+    /// Whether a transfer currently owns the bus.
+    pub fn active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// The address the active transfer is reading from. Without one, this
+    /// is 0.
+    pub fn source(&self) -> u16 {
+        self.active.map_or(0, |transfer| transfer.source + transfer.offset)
+    }
+
+    /// Advances the engine by an M-cycle. Returns the source address and the
+    /// OAM offset of the byte to copy during this cycle.
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        if let Some(transfer) = self.active {
+            if transfer.offset == OAM_DMA_LENGTH - 1 {
+                self.active = None;
+            } else {
+                self.active = Some(Transfer {
+                    offset: transfer.offset + 1,
+                    ..transfer
+                });
+            }
+        }
+
+        if let Some(pending) = self.pending {
+            if pending.delay == 0 {
+                self.pending = None;
+                self.active = Some(Transfer {
+                    source: pending.source,
+                    offset: 0,
+                });
+            } else {
+                self.pending = Some(Pending {
+                    delay: pending.delay - 1,
+                    ..pending
+                });
+            }
+        }
+
+        self.active.map(|transfer| {
+            let source = transfer.source + transfer.offset;
+            // The top of the address space isn't reachable by DMA. Those
+            // pages read from work RAM instead, like echo RAM.
+            if source >= 0xE000 {
+                (source - 0x2000, transfer.offset)
+            } else {
+                (source, transfer.offset)
+            }
+        })
+    }
+}
+
+
+/// The M-cycles between writing 0xFF46 and the first byte being copied.
+const START_DELAY: u8 = 1;
+const OAM_DMA_LENGTH: u16 = 0xA0;
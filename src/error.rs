@@ -0,0 +1,58 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading a ROM file failed.
+    Io(io::Error),
+    /// The ROM is too short to hold a cartridge header.
+    TruncatedRom { len: usize },
+    /// The cartridge header declares an unknown RAM size.
+    InvalidRamSize { code: u8 },
+    /// The cartridge uses a memory bank controller that isn't emulated.
+    UnsupportedCartridge { code: u8 },
+    /// The CPU executed an illegal opcode, which locks it up.
+    IllegalOpcode { opcode: u8, pc: u16 },
+    /// The audio sample rate is zero, or above the rate the APU output
+    /// changes at.
+    InvalidSampleRate { rate: u32 },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(fmt, "I/O error: {}", err),
+            Error::TruncatedRom { len } =>
+                write!(fmt, "ROM of {} bytes is too short to hold a header",
+                       len),
+            Error::InvalidRamSize { code } =>
+                write!(fmt, "invalid RAM size code {:#04X}", code),
+            Error::UnsupportedCartridge { code } =>
+                write!(fmt, "unsupported cartridge type {:#04X}", code),
+            Error::IllegalOpcode { opcode, pc } =>
+                write!(fmt, "illegal opcode {:#04X} at {:#06X}", opcode, pc),
+            Error::InvalidSampleRate { rate } =>
+                write!(fmt, "unsupported sample rate of {} Hz", rate),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
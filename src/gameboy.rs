@@ -1,10 +1,17 @@
+use std::io;
+
+use error::Result;
+use joypad::{Button, ButtonState};
 use mmu::MMU;
-use cartridge::Cartridge;
+use ppu::Renderer;
+use serial::LinkCable;
+use cartridge::{Cartridge, RtcClock};
 use cpu::Cpu;
 
 pub struct Gameboy {
     mmu: MMU,
     cpu: Cpu,
+    cycles: u64,
 }
 
 impl Gameboy {
@@ -12,16 +19,158 @@ impl Gameboy {
         Gameboy {
             mmu: MMU::new(cart),
             cpu: Cpu::new(),
+            cycles: 0,
+        }
+    }
+
+    /// Runs the CPU for a single instruction. Returns the number of M-cycles
+    /// that elapsed.
+    pub fn tick(&mut self) -> Result<u8> {
+        let cycles = match self.cpu.tick(&mut self.mmu) {
+            Ok(cycles) => cycles,
+            Err(err) => {
+                // Account for the bus accesses made before the error, so
+                // they don't carry over into the next instruction.
+                let cycles = self.mmu.finish_instruction(0);
+                self.cycles += cycles as u64;
+                return Err(err);
+            }
+        };
+        if self.cpu.stopped() {
+            self.mmu.finish_instruction(0);
+        } else {
+            self.mmu.finish_instruction(cycles);
+        }
+        self.cycles += cycles as u64;
+        Ok(cycles)
+    }
+
+    /// Runs until the PPU finishes drawing the current frame. While the LCD
+    /// is off, runs for one frame's worth of cycles instead.
+    pub fn run_frame(&mut self) -> Result<()> {
+        let frame = self.mmu.ppu().frames();
+        let start = self.cycles;
+        while self.mmu.ppu().frames() == frame &&
+                self.cycles - start < CYCLES_PER_FRAME {
+            try!(self.tick());
         }
+        Ok(())
+    }
+
+    /// The screen, `SCREEN_WIDTH` by `SCREEN_HEIGHT` shades in row-major
+    /// order. Shade 0 is white and shade 3 is black. The PPU draws into it
+    /// as it goes, so it holds a complete frame after `run_frame`.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.mmu.ppu().framebuffer()
+    }
+
+    /// Presses or releases a button. The change is seen by the next
+    /// instruction that reads the joypad.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let mut buttons = self.mmu.joypad().buttons();
+        buttons.set(button, pressed);
+        self.mmu.joypad_mut().set_buttons(buttons);
+    }
+
+    /// Replaces the state of every button at once.
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        self.mmu.joypad_mut().set_buttons(buttons);
+    }
+
+    pub fn buttons(&self) -> ButtonState {
+        self.mmu.joypad().buttons()
+    }
+
+    /// Plugs a link cable into the serial port, replacing any previous one.
+    pub fn connect_link<L: LinkCable + 'static>(&mut self, link: L) {
+        self.mmu.connect_link(Box::new(link));
+    }
+
+    /// Unplugs the link cable. Transfers then receive 0xFF.
+    pub fn disconnect_link(&mut self) -> Option<Box<dyn LinkCable>> {
+        self.mmu.disconnect_link()
+    }
+
+    /// Selects how the PPU draws the screen. The change takes effect from the
+    /// next scanline.
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.mmu.ppu_mut().set_renderer(renderer);
+    }
+
+    /// Sets the rate of the audio samples, in Hz. Samples not yet drained
+    /// are dropped. Fails if the rate is zero or above 1,048,576 Hz, the
+    /// rate the APU output changes at.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<()> {
+        self.mmu.sound_mut().set_sample_rate(sample_rate)
     }
 
-    pub fn tick(&mut self) {
-        self.cpu.tick(&mut self.mmu);
+    pub fn sample_rate(&self) -> u32 {
+        self.mmu.sound().sample_rate()
     }
 
-    pub fn run(&mut self) {
+    /// Sets how many stereo frames of audio are buffered. Once it is full,
+    /// the oldest frames are dropped. Samples not yet drained are dropped.
+    pub fn set_audio_buffer_size(&mut self, frames: usize) {
+        self.mmu.sound_mut().set_buffer_size(frames);
+    }
+
+    /// The number of stereo frames of audio ready to be drained.
+    pub fn samples_available(&self) -> usize {
+        self.mmu.sound().samples_available()
+    }
+
+    /// Moves the audio generated so far into `out`, as interleaved left and
+    /// right samples, and returns the number of frames written. The output
+    /// only depends on the emulated input, so it is the same on every run.
+    pub fn drain_samples(&mut self, out: &mut [i16]) -> usize {
+        self.mmu.sound_mut().drain_samples(out)
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.mmu.ppu().renderer()
+    }
+
+    /// The number of frames the PPU has completed since power on.
+    pub fn frames(&self) -> u64 {
+        self.mmu.ppu().frames()
+    }
+
+    /// Selects what drives the real-time clock of MBC3 cartridges. Has no
+    /// effect on cartridges without a clock.
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.mmu.cartridge_mut().set_rtc_clock(clock);
+    }
+
+    /// Registers a callback for rumble cartridges, called with the new
+    /// motor state whenever the game turns the motor on or off.
+    pub fn set_rumble_callback<F>(&mut self, callback: F)
+            where F: FnMut(bool) + 'static {
+        self.mmu.cartridge_mut().set_rumble_callback(callback);
+    }
+
+    /// Writes the cartridge's battery-backed RAM to its save file.
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        self.mmu.cartridge_mut().flush_save()
+    }
+
+    /// Whether the boot ROM is still mapped into memory.
+    pub fn bootrom_mapped(&self) -> bool {
+        self.mmu.bootrom_mapped()
+    }
+
+    /// The total number of M-cycles elapsed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Runs until an error occurs.
+    pub fn run(&mut self) -> Result<()> {
         loop {
-            self.tick();
+            try!(self.tick());
         }
     }
 }
+
+
+/// The length of a frame, 154 scanlines of 114 M-cycles each.
+const CYCLES_PER_FRAME: u64 = 154 * 114;
@@ -0,0 +1,451 @@
+use error::{Error, Result};
+
+
+/// The cartridge header, found at 0x0100-0x014F of every ROM.
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    /// The code executed after the boot ROM, usually a jump to the game.
+    pub entry_point: [u8; 4],
+    /// Whether the Nintendo logo matches the one the boot ROM checks for.
+    pub logo_valid: bool,
+    pub title: String,
+    /// The four character manufacturer code of later cartridges.
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    /// The two character licensee code, used when `old_licensee_code` is
+    /// 0x33.
+    pub new_licensee_code: Option<String>,
+    pub old_licensee_code: u8,
+    pub sgb_support: bool,
+    pub cartridge_type: CartridgeType,
+    /// The ROM size in bytes, or `None` for an unknown size code.
+    pub rom_size: Option<usize>,
+    /// The external RAM size in bytes, or `None` for an unknown size code.
+    pub ram_size: Option<usize>,
+    pub destination: Destination,
+    pub version: u8,
+    pub header_checksum: u8,
+    /// Whether `header_checksum` matches the header. The boot ROM refuses to
+    /// start games when it does not.
+    pub header_checksum_valid: bool,
+    pub global_checksum: u16,
+    /// Whether `global_checksum` matches the ROM. This is not verified by
+    /// the hardware.
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    pub fn from_rom(rom: &[u8]) -> Result<Self> {
+        if rom.len() < HEADER_END {
+            return Err(Error::TruncatedRom { len: rom.len() });
+        }
+
+        let cgb_support = match rom[0x0143] {
+            0xC0 => CgbSupport::Required,
+            flag if flag & 0x80 != 0 => CgbSupport::Supported,
+            _ => CgbSupport::None,
+        };
+
+        let manufacturer_code = &rom[0x013F..0x0143];
+        let manufacturer_code = if cgb_support != CgbSupport::None &&
+                manufacturer_code.iter().all(|b| b.is_ascii_uppercase() ||
+                                                 b.is_ascii_digit()) {
+            Some(ascii_string(manufacturer_code))
+        } else {
+            None
+        };
+
+        let title_end = match (cgb_support, &manufacturer_code) {
+            (CgbSupport::None, _) => 0x0144,
+            (_, &Some(_)) => 0x013F,
+            (_, &None) => 0x0143,
+        };
+        let title = ascii_string(&rom[0x0134..title_end]);
+
+        let old_licensee_code = rom[0x014B];
+        let new_licensee_code = if old_licensee_code == 0x33 {
+            Some(ascii_string(&rom[0x0144..0x0146]))
+        } else {
+            None
+        };
+
+        let rom_size = match rom[0x0148] {
+            code @ 0x00...0x08 => Some((32 * 1024) << code),
+            0x52 => Some(72 * ROM_BANK_SIZE),
+            0x53 => Some(80 * ROM_BANK_SIZE),
+            0x54 => Some(96 * ROM_BANK_SIZE),
+            _ => None,
+        };
+
+        let ram_size = match rom[0x0149] {
+            0x00 => Some(0),
+            0x01 => Some(2 * 1024),
+            0x02 => Some(8 * 1024),
+            0x03 => Some(32 * 1024),
+            0x04 => Some(128 * 1024),
+            0x05 => Some(64 * 1024),
+            _ => None,
+        };
+
+        let header_checksum = rom[0x014D];
+        let computed_header_checksum = rom[0x0134..0x014D].iter()
+            .fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+
+        let global_checksum = ((rom[0x014E] as u16) << 8) | rom[0x014F] as u16;
+        let computed_global_checksum = rom.iter().enumerate()
+            .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+            .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+
+        Ok(CartridgeHeader {
+            entry_point: [rom[0x0100], rom[0x0101], rom[0x0102], rom[0x0103]],
+            logo_valid: rom[0x0104..0x0134] == NINTENDO_LOGO[..],
+            title: title,
+            manufacturer_code: manufacturer_code,
+            cgb_support: cgb_support,
+            new_licensee_code: new_licensee_code,
+            old_licensee_code: old_licensee_code,
+            sgb_support: rom[0x0146] == 0x03,
+            cartridge_type: CartridgeType::from(rom[0x0147]),
+            rom_size: rom_size,
+            ram_size: ram_size,
+            destination: if rom[0x014A] == 0x00 {
+                Destination::Japanese
+            } else {
+                Destination::Overseas
+            },
+            version: rom[0x014C],
+            header_checksum: header_checksum,
+            header_checksum_valid: header_checksum == computed_header_checksum,
+            global_checksum: global_checksum,
+            global_checksum_valid: global_checksum == computed_global_checksum,
+        })
+    }
+}
+
+/// Decodes a fixed-length, NUL-padded header string. Bytes that aren't
+/// printable ASCII are replaced.
+fn ascii_string(bytes: &[u8]) -> String {
+    bytes.iter()
+        .take_while(|b| **b != 0x00)
+        .map(|&b| match b {
+            b' ' => ' ',
+            b if b.is_ascii_graphic() => b as char,
+            _ => '?',
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+    /// A monochrome game.
+    None,
+    /// A game with Game Boy Color enhancements that also runs in
+    /// monochrome.
+    Supported,
+    /// A game that only runs on the Game Boy Color.
+    Required,
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Destination { Japanese, Overseas }
+
+
+/// The hardware on a cartridge, decoded from header byte 0x0147.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl From<u8> for CartridgeType {
+    fn from(code: u8) -> Self {
+        use self::Mapper::*;
+
+        let (mapper, ram, battery, timer, rumble) = match code {
+            0x00 => (RomOnly, false, false, false, false),
+            0x01 => (Mbc1, false, false, false, false),
+            0x02 => (Mbc1, true, false, false, false),
+            0x03 => (Mbc1, true, true, false, false),
+            // MBC2 RAM is built into the controller.
+            0x05 => (Mbc2, true, false, false, false),
+            0x06 => (Mbc2, true, true, false, false),
+            0x08 => (RomOnly, true, false, false, false),
+            0x09 => (RomOnly, true, true, false, false),
+            0x0B => (Mmm01, false, false, false, false),
+            0x0C => (Mmm01, true, false, false, false),
+            0x0D => (Mmm01, true, true, false, false),
+            0x0F => (Mbc3, false, true, true, false),
+            0x10 => (Mbc3, true, true, true, false),
+            0x11 => (Mbc3, false, false, false, false),
+            0x12 => (Mbc3, true, false, false, false),
+            0x13 => (Mbc3, true, true, false, false),
+            0x19 => (Mbc5, false, false, false, false),
+            0x1A => (Mbc5, true, false, false, false),
+            0x1B => (Mbc5, true, true, false, false),
+            0x1C => (Mbc5, false, false, false, true),
+            0x1D => (Mbc5, true, false, false, true),
+            0x1E => (Mbc5, true, true, false, true),
+            0x20 => (Mbc6, false, false, false, false),
+            0x22 => (Mbc7, true, true, false, true),
+            0xFC => (PocketCamera, false, false, false, false),
+            0xFD => (Tama5, false, false, false, false),
+            0xFE => (HuC3, false, false, false, false),
+            0xFF => (HuC1, true, true, false, false),
+            _ => (Unknown, false, false, false, false),
+        };
+
+        CartridgeType {
+            code: code,
+            mapper: mapper,
+            ram: ram,
+            battery: battery,
+            timer: timer,
+            rumble: rumble,
+        }
+    }
+}
+
+
+/// The memory bank controller on a cartridge.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+    Unknown,
+}
+
+
+const HEADER_END: usize = 0x0150;
+const ROM_BANK_SIZE: usize = 0x4000;
+
+const NINTENDO_LOGO: [u8; 48] =
+    [0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+     0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+     0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+     0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E];
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge::Cartridge;
+
+    /// A hand-built header for an MBC3 cartridge with 128 KiB of ROM and
+    /// 32 KiB of RAM, and with valid checksums.
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0; HEADER_END];
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x0134..0x013D].copy_from_slice(b"TEST GAME");
+        rom[0x0144..0x0146].copy_from_slice(b"01");
+        rom[0x0146] = 0x03;
+        rom[0x0147] = 0x13;
+        rom[0x0148] = 0x02;
+        rom[0x0149] = 0x03;
+        rom[0x014A] = 0x01;
+        rom[0x014B] = 0x33;
+        rom[0x014C] = 0x02;
+        rom[0x014D] = 0xBB;
+        rom[0x014E] = 0x1A;
+        rom[0x014F] = 0x41;
+        rom
+    }
+
+    fn parse(rom: &[u8]) -> CartridgeHeader {
+        CartridgeHeader::from_rom(rom).unwrap()
+    }
+
+    #[test]
+    fn fields() {
+        let header = parse(&test_rom());
+        assert_eq!(header.entry_point, [0x00, 0xC3, 0x50, 0x01]);
+        assert_eq!(header.title, "TEST GAME");
+        assert_eq!(header.manufacturer_code, None);
+        assert_eq!(header.cgb_support, CgbSupport::None);
+        assert_eq!(header.new_licensee_code, Some("01".to_string()));
+        assert_eq!(header.old_licensee_code, 0x33);
+        assert!(header.sgb_support);
+        assert_eq!(header.cartridge_type.mapper, Mapper::Mbc3);
+        assert_eq!(header.rom_size, Some(128 * 1024));
+        assert_eq!(header.ram_size, Some(32 * 1024));
+        assert_eq!(header.destination, Destination::Overseas);
+        assert_eq!(header.version, 0x02);
+    }
+
+    #[test]
+    fn title() {
+        // Monochrome games use all 16 bytes.
+        let mut rom = test_rom();
+        rom[0x0134..0x0144].copy_from_slice(b"SIXTEEN BYTES  X");
+        assert_eq!(parse(&rom).title, "SIXTEEN BYTES  X");
+
+        // Color games use the last byte for the CGB flag, and later ones
+        // the four before it for a manufacturer code.
+        rom[0x0143] = 0x80;
+        let header = parse(&rom);
+        assert_eq!(header.title, "SIXTEEN BYTES");
+        assert_eq!(header.cgb_support, CgbSupport::Supported);
+        // Spaces aren't allowed in a manufacturer code.
+        assert_eq!(header.manufacturer_code, None);
+        rom[0x013F..0x0143].copy_from_slice(b"AB1C");
+        rom[0x0143] = 0xC0;
+        let header = parse(&rom);
+        assert_eq!(header.title, "SIXTEEN BYT");
+        assert_eq!(header.cgb_support, CgbSupport::Required);
+        assert_eq!(header.manufacturer_code, Some("AB1C".to_string()));
+
+        // Unprintable bytes are replaced, and padding is dropped.
+        let mut rom = test_rom();
+        rom[0x0134..0x0144].copy_from_slice(b"A\x01B   \0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(parse(&rom).title, "A?B");
+    }
+
+    #[test]
+    fn cartridge_types() {
+        let types = [
+            (0x00, Mapper::RomOnly, false, false, false, false),
+            (0x03, Mapper::Mbc1, true, true, false, false),
+            (0x05, Mapper::Mbc2, true, false, false, false),
+            (0x0F, Mapper::Mbc3, false, true, true, false),
+            (0x10, Mapper::Mbc3, true, true, true, false),
+            (0x1B, Mapper::Mbc5, true, true, false, false),
+            (0x1C, Mapper::Mbc5, false, false, false, true),
+            (0x22, Mapper::Mbc7, true, true, false, true),
+            (0xFC, Mapper::PocketCamera, false, false, false, false),
+            (0x04, Mapper::Unknown, false, false, false, false),
+        ];
+        for &(code, mapper, ram, battery, timer, rumble) in types.iter() {
+            assert_eq!(CartridgeType::from(code), CartridgeType {
+                code: code,
+                mapper: mapper,
+                ram: ram,
+                battery: battery,
+                timer: timer,
+                rumble: rumble,
+            });
+        }
+    }
+
+    #[test]
+    fn unsupported_cartridge() {
+        let mut rom = test_rom();
+        rom[0x0147] = 0xFC;
+        match Cartridge::from_buffer(rom) {
+            Err(Error::UnsupportedCartridge { code: 0xFC }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn rom_sizes() {
+        let sizes = [
+            (0x00, Some(32 * 1024)),
+            (0x01, Some(64 * 1024)),
+            (0x05, Some(1024 * 1024)),
+            (0x08, Some(8 * 1024 * 1024)),
+            (0x52, Some(72 * ROM_BANK_SIZE)),
+            (0x53, Some(80 * ROM_BANK_SIZE)),
+            (0x54, Some(96 * ROM_BANK_SIZE)),
+            (0x09, None),
+        ];
+        let mut rom = test_rom();
+        for &(code, size) in sizes.iter() {
+            rom[0x0148] = code;
+            assert_eq!(parse(&rom).rom_size, size, "code {:#04X}", code);
+        }
+    }
+
+    #[test]
+    fn ram_sizes() {
+        let sizes = [
+            (0x00, Some(0)),
+            (0x01, Some(2 * 1024)),
+            (0x02, Some(8 * 1024)),
+            (0x03, Some(32 * 1024)),
+            (0x04, Some(128 * 1024)),
+            (0x05, Some(64 * 1024)),
+            (0x06, None),
+        ];
+        let mut rom = test_rom();
+        for &(code, size) in sizes.iter() {
+            rom[0x0149] = code;
+            assert_eq!(parse(&rom).ram_size, size, "code {:#04X}", code);
+        }
+    }
+
+    #[test]
+    fn invalid_ram_size() {
+        let mut rom = test_rom();
+        rom[0x0149] = 0x06;
+        match Cartridge::from_buffer(rom.clone()) {
+            Err(Error::InvalidRamSize { code: 0x06 }) => (),
+            result => panic!("{:?}", result),
+        }
+
+        // MBC2 has its own RAM, so the size code isn't used.
+        rom[0x0147] = 0x06;
+        assert!(Cartridge::from_buffer(rom).is_ok());
+    }
+
+    #[test]
+    fn checksums() {
+        let header = parse(&test_rom());
+        assert_eq!(header.header_checksum, 0xBB);
+        assert!(header.header_checksum_valid);
+        assert_eq!(header.global_checksum, 0x1A41);
+        assert!(header.global_checksum_valid);
+
+        // The header checksum covers 0x0134-0x014C.
+        let mut rom = test_rom();
+        rom[0x0100] = 0xFF;
+        assert!(parse(&rom).header_checksum_valid);
+        rom[0x014C] = 0x03;
+        assert!(!parse(&rom).header_checksum_valid);
+
+        // The global checksum covers everything but itself.
+        let mut rom = test_rom();
+        rom.push(0x01);
+        assert!(!parse(&rom).global_checksum_valid);
+        rom[0x014F] = 0x42;
+        assert!(parse(&rom).global_checksum_valid);
+    }
+
+    #[test]
+    fn logo() {
+        let mut rom = test_rom();
+        assert!(parse(&rom).logo_valid);
+        rom[0x0133] ^= 0x01;
+        assert!(!parse(&rom).logo_valid);
+    }
+
+    #[test]
+    fn truncated_rom() {
+        let rom = test_rom();
+        match CartridgeHeader::from_rom(&rom[..HEADER_END - 1]) {
+            Err(Error::TruncatedRom { len }) => assert_eq!(len, HEADER_END - 1),
+            result => panic!("{:?}", result),
+        }
+        match Cartridge::from_buffer(Vec::new()) {
+            Err(Error::TruncatedRom { len: 0 }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+
+/// The interrupt sources, in priority order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt { VBlank, LcdStat, Timer, Serial, Joypad }
+
+const INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LcdStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    /// The bit of this interrupt in the IE and IF registers.
+    pub fn bit(&self) -> u8 {
+        match *self {
+            Interrupt::VBlank => 0,
+            Interrupt::LcdStat => 1,
+            Interrupt::Timer => 2,
+            Interrupt::Serial => 3,
+            Interrupt::Joypad => 4,
+        }
+    }
+
+    /// The address the CPU jumps to when servicing this interrupt.
+    pub fn vector(&self) -> u16 {
+        0x40 + 8 * (self.bit() as u16)
+    }
+
+    /// Returns the highest priority interrupt set in the given flags.
+    pub fn highest_priority(flags: u8) -> Option<Interrupt> {
+        INTERRUPTS.iter().cloned().find(|i| flags & (1 << i.bit()) != 0)
+    }
+}
+
+
+/// The interrupt request line shared between the CPU and the devices that
+/// raise interrupts. Its state is visible on the bus as the IF register.
+/// Cloning the line yields another handle to the same flags.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptLine {
+    flags: Rc<Cell<u8>>,
+}
+
+impl InterruptLine {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn request(&self, interrupt: Interrupt) {
+        self.flags.set(self.flags.get() | (1 << interrupt.bit()));
+    }
+
+    pub fn acknowledge(&self, interrupt: Interrupt) {
+        self.flags.set(self.flags.get() & !(1 << interrupt.bit()));
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags.get()
+    }
+
+    pub fn set_flags(&self, val: u8) {
+        self.flags.set(val & INTERRUPT_MASK);
+    }
+}
+
+
+pub const INTERRUPT_MASK: u8 = 0b1_1111;
@@ -1,34 +1,145 @@
+use interrupts::{InterruptLine, INTERRUPT_MASK};
+use joypad::Joypad;
+use serial::{LinkCable, Serial};
 use sound::SoundRegisters;
+use timer::Timer;
+use utils::BitOps;
 
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct IoPorts {
+    interrupts: InterruptLine,
+    joypad: Joypad,
+    serial: Serial,
     sound: SoundRegisters,
+    timer: Timer,
 }
 
 impl IoPorts {
-    pub fn new() -> Self {
+    pub fn new(interrupts: InterruptLine) -> Self {
         IoPorts {
+            joypad: Joypad::new(interrupts.clone()),
+            serial: Serial::new(interrupts.clone()),
+            timer: Timer::new(interrupts.clone()),
+            interrupts: interrupts,
             sound: SoundRegisters::new(),
-            ..Default::default()
         }
     }
 
+    /// Advances the IO devices by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            let counter = self.timer.counter();
+            self.timer.tick(1);
+            self.serial.tick(counter, self.timer.counter());
+            self.sound.tick(1);
+            self.check_frame_sequencer(counter);
+        }
+    }
+
+    pub fn reset_divider(&mut self) {
+        let counter = self.timer.counter();
+        self.timer.reset_divider();
+        self.check_frame_sequencer(counter);
+    }
+
+    /// Clocks the APU's frame sequencer if the system counter changed from
+    /// `old_counter` with a falling edge on its bit. Resetting DIV can cause
+    /// one too.
+    fn check_frame_sequencer(&mut self, old_counter: u16) {
+        if old_counter.get_bit(FRAME_SEQUENCER_BIT) &&
+                !self.timer.counter().get_bit(FRAME_SEQUENCER_BIT) {
+            self.sound.clock_frame_sequencer();
+        }
+    }
+
+    /// Reads from a port. Unused bits read as 1, and unmapped ports read as
+    /// 0xFF.
     pub fn read(&self, port: u8) -> u8 {
-        match port {
+        let val = match port {
+            0x00 => self.joypad.read(),
+            0x01...0x02 => self.serial.read(port),
+            0x04...0x07 => self.timer.read(port),
+            0x0F => self.interrupts.flags(),
             0x10...0x3F => self.sound.read(port),
-            _ => panic!("Invalid port for IoPort::read: {:#X}", port),
-        }
+            _ => 0xFF,
+        };
+        val | READ_MASKS[port as usize]
     }
 
+    /// Writes to a port. Writes to unmapped ports are ignored.
     pub fn write(&mut self, port: u8, val: u8) {
         match port {
+            0x00 => self.joypad.write(val),
+            0x01...0x02 => self.serial.write(port, val),
+            0x04...0x07 => {
+                let counter = self.timer.counter();
+                self.timer.write(port, val);
+                self.check_frame_sequencer(counter);
+            }
+            0x0F => self.interrupts.set_flags(val),
             0x10...0x3F => self.sound.write(port, val),
-            _ => panic!("Invalid port for IoPort::write: {:#X}", port),
+            _ => (),
         }
     }
 
-    pub fn sound_registers(&mut self) -> &mut SoundRegisters {
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    pub fn connect_link(&mut self, link: Box<dyn LinkCable>) {
+        self.serial.connect(link);
+    }
+
+    pub fn disconnect_link(&mut self) -> Option<Box<dyn LinkCable>> {
+        self.serial.disconnect()
+    }
+
+    pub fn sound(&self) -> &SoundRegisters {
+        &self.sound
+    }
+
+    pub fn sound_mut(&mut self) -> &mut SoundRegisters {
         &mut self.sound
     }
 }
+
+
+/// The APU's frame sequencer is clocked by falling edges of this bit of the
+/// system counter, which is bit 4 of DIV.
+const FRAME_SEQUENCER_BIT: u8 = 12;
+
+/// The bits of each port that always read as 1, either because they are
+/// unused or because the register is write-only. Unmapped ports read as
+/// 0xFF.
+const READ_MASKS: [u8; 0x80] = [
+    // 0xFF00: P1, SB, SC, -, DIV, TIMA, TMA, TAC
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8,
+    // 0xFF08: -, -, -, -, -, -, -, IF
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, !INTERRUPT_MASK,
+    // 0xFF10: NR10, NR11, NR12, NR13, NR14, -, NR21, NR22
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00,
+    // 0xFF18: NR23, NR24, NR30, NR31, NR32, NR33, NR34, -
+    0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    // 0xFF20: NR41, NR42, NR43, NR44, NR50, NR51, NR52, -
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF,
+    // 0xFF28: unused
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    // 0xFF30: wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // 0xFF40: LCDC, STAT, SCY, SCX, LY, LYC, DMA, BGP
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // 0xFF48: OBP0, OBP1, WY, WX, then unmapped up to HRAM
+    0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
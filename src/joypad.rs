@@ -0,0 +1,209 @@
+use interrupts::{Interrupt, InterruptLine};
+use utils::BitOps;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Button { Right, Left, Up, Down, A, B, Select, Start }
+
+
+/// Which buttons are held down.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ButtonState {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl ButtonState {
+    pub fn pressed(&self, button: Button) -> bool {
+        match button {
+            Button::Right => self.right,
+            Button::Left => self.left,
+            Button::Up => self.up,
+            Button::Down => self.down,
+            Button::A => self.a,
+            Button::B => self.b,
+            Button::Select => self.select,
+            Button::Start => self.start,
+        }
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        *match button {
+            Button::Right => &mut self.right,
+            Button::Left => &mut self.left,
+            Button::Up => &mut self.up,
+            Button::Down => &mut self.down,
+            Button::A => &mut self.a,
+            Button::B => &mut self.b,
+            Button::Select => &mut self.select,
+            Button::Start => &mut self.start,
+        } = pressed;
+    }
+}
+
+
+/// The P1 register. The game selects the direction keys, the buttons, or
+/// both, and reads the selected keys back on four active-low input lines.
+#[derive(Debug)]
+pub struct Joypad {
+    interrupts: InterruptLine,
+    /// The selection bits, 4 and 5. A group is selected when its bit is 0.
+    select: u8,
+    buttons: ButtonState,
+}
+
+impl Joypad {
+    pub fn new(interrupts: InterruptLine) -> Self {
+        Joypad {
+            interrupts: interrupts,
+            select: SELECT_MASK,
+            buttons: ButtonState::default(),
+        }
+    }
+
+    pub fn buttons(&self) -> ButtonState {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        let lines = self.input_lines();
+        self.buttons = buttons;
+        self.detect_edge(lines);
+    }
+
+    /// Returns the four input lines. A line is low while any selected key
+    /// wired to it is pressed.
+    pub fn input_lines(&self) -> u8 {
+        let mut lines = 0b1111;
+        if !self.select.get_bit(4) {
+            lines &= !group_bits(self.buttons.right, self.buttons.left,
+                                 self.buttons.up, self.buttons.down);
+        }
+        if !self.select.get_bit(5) {
+            lines &= !group_bits(self.buttons.a, self.buttons.b,
+                                 self.buttons.select, self.buttons.start);
+        }
+        lines
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.input_lines()
+    }
+
+    pub fn write(&mut self, val: u8) {
+        let lines = self.input_lines();
+        self.select = val & SELECT_MASK;
+        self.detect_edge(lines);
+    }
+
+    /// Requests the joypad interrupt when any input line goes low.
+    fn detect_edge(&mut self, old_lines: u8) {
+        if old_lines & !self.input_lines() != 0 {
+            self.interrupts.request(Interrupt::Joypad);
+        }
+    }
+}
+
+fn group_bits(bit0: bool, bit1: bool, bit2: bool, bit3: bool) -> u8 {
+    (bit0 as u8) | (bit1 as u8) << 1 | (bit2 as u8) << 2 | (bit3 as u8) << 3
+}
+
+
+const SELECT_MASK: u8 = 0b0011_0000;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joypad_requested(interrupts: &InterruptLine) -> bool {
+        interrupts.flags().get_bit(Interrupt::Joypad.bit())
+    }
+
+    fn press(joypad: &mut Joypad, button: Button, pressed: bool) {
+        let mut buttons = joypad.buttons();
+        buttons.set(button, pressed);
+        joypad.set_buttons(buttons);
+    }
+
+    #[test]
+    fn select_lines() {
+        let mut joypad = Joypad::new(InterruptLine::new());
+        press(&mut joypad, Button::Left, true);
+        press(&mut joypad, Button::Start, true);
+        // With nothing selected, every line reads high.
+        assert_eq!(joypad.read(), 0xFF);
+
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xE0 | 0b1101);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD0 | 0b0111);
+        // With both selected, the groups share the lines.
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC0 | 0b0101);
+        // Only the selection bits are writable.
+        joypad.write(0xEF);
+        assert_eq!(joypad.read(), 0xE0 | 0b1101);
+    }
+
+    #[test]
+    fn keys_are_active_low() {
+        let mut joypad = Joypad::new(InterruptLine::new());
+        joypad.write(0x10);
+        let keys = [Button::A, Button::B, Button::Select, Button::Start];
+        for (bit, &button) in keys.iter().enumerate() {
+            press(&mut joypad, button, true);
+            assert_eq!(joypad.read() & 0x0F, !(1 << bit) & 0x0F);
+            press(&mut joypad, button, false);
+            assert_eq!(joypad.read() & 0x0F, 0x0F);
+        }
+
+        joypad.write(0x20);
+        let keys = [Button::Right, Button::Left, Button::Up, Button::Down];
+        for (bit, &button) in keys.iter().enumerate() {
+            press(&mut joypad, button, true);
+            assert_eq!(joypad.read() & 0x0F, !(1 << bit) & 0x0F);
+            press(&mut joypad, button, false);
+        }
+    }
+
+    #[test]
+    fn interrupt_on_falling_line() {
+        let interrupts = InterruptLine::new();
+        let mut joypad = Joypad::new(interrupts.clone());
+        joypad.write(0x20);
+
+        // Keys that aren't selected don't change the lines.
+        press(&mut joypad, Button::A, true);
+        assert!(!joypad_requested(&interrupts));
+        press(&mut joypad, Button::Up, true);
+        assert!(joypad_requested(&interrupts));
+
+        // Selecting a group with a key held pulls its line low too.
+        interrupts.set_flags(0);
+        joypad.write(0x00);
+        assert!(joypad_requested(&interrupts));
+
+        // A key on a line that is already low doesn't, nor does a release.
+        interrupts.set_flags(0);
+        press(&mut joypad, Button::Right, true);
+        press(&mut joypad, Button::Select, true);
+        press(&mut joypad, Button::Up, false);
+        assert!(!joypad_requested(&interrupts));
+        press(&mut joypad, Button::Down, true);
+        assert!(joypad_requested(&interrupts));
+
+        // Deselecting everything only raises lines.
+        interrupts.set_flags(0);
+        joypad.write(0x30);
+        assert!(!joypad_requested(&interrupts));
+        joypad.write(0x10);
+        assert!(joypad_requested(&interrupts));
+    }
+}
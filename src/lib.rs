@@ -3,11 +3,26 @@
 mod bootrom;
 mod cartridge;
 mod cpu;
+mod dma;
+mod error;
+mod interrupts;
 mod io;
+mod joypad;
 mod gameboy;
+mod header;
 mod mmu;
+mod ppu;
+mod serial;
 mod sound;
+mod timer;
 mod utils;
 
-pub use cartridge::Cartridge;
+pub use cartridge::{Cartridge, RtcClock};
+pub use error::{Error, Result};
 pub use gameboy::Gameboy;
+pub use header::{CartridgeHeader, CartridgeType, CgbSupport, Destination,
+                 Mapper};
+pub use joypad::{Button, ButtonState};
+pub use ppu::{Renderer, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use serial::{CaptureLink, LinkCable, LoopbackLink, PairedLink};
+pub use sound::{DEFAULT_AUDIO_BUFFER_SIZE, DEFAULT_SAMPLE_RATE};
@@ -29,5 +29,7 @@ fn main() {
     println!("Loaded ROM with title: {}", cart.title());
     let mut gameboy = Gameboy::new(cart);
     println!("Running...\n");
-    gameboy.run();
+    if let Err(err) = gameboy.run() {
+        println!("Emulation stopped: {}", err);
+    }
 }
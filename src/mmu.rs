@@ -1,6 +1,12 @@
 use bootrom::DEFAULT_BOOT_ROM;
 use cartridge::Cartridge;
+use dma::OamDma;
+use interrupts::{Interrupt, InterruptLine};
 use io::IoPorts;
+use joypad::Joypad;
+use ppu::Ppu;
+use serial::LinkCable;
+use sound::SoundRegisters;
 use utils::WordOps;
 
 
@@ -8,71 +14,460 @@ pub struct MMU {
     cart: Cartridge,
     bootrom: Vec<u8>,
     wram: Vec<u8>,
-    vram: Vec<u8>,
+    hram: Vec<u8>,
+    bootrom_mapped: bool,
     io_ports: IoPorts,
+    ppu: Ppu,
+    dma: OamDma,
+    /// The byte the DMA engine last copied, which is what the CPU sees when
+    /// reading from the bus the transfer is using.
+    dma_value: u8,
+    interrupts: InterruptLine,
+    interrupt_enable: u8,
+    /// The M-cycles the devices have already been advanced by bus accesses
+    /// during the current instruction.
+    access_cycles: u8,
 }
 
 impl MMU {
     pub fn new(cart: Cartridge) -> Self {
+        let interrupts = InterruptLine::new();
         MMU {
             cart: cart,
             bootrom: Vec::from(&DEFAULT_BOOT_ROM[..]),
             wram: vec![0; (WRAM_END-WRAM_START) as usize],
-            vram: vec![0; (VRAM_END-VRAM_START) as usize],
-            io_ports: IoPorts::new(),
+            hram: vec![0; (HRAM_END-HRAM_START) as usize],
+            bootrom_mapped: true,
+            io_ports: IoPorts::new(interrupts.clone()),
+            ppu: Ppu::new(interrupts.clone()),
+            dma: OamDma::default(),
+            dma_value: 0xFF,
+            interrupts: interrupts,
+            interrupt_enable: 0,
+            access_cycles: 0,
         }
     }
 
-    pub fn read8(&self, addr: u16) -> u8 {
-        if BOOTROM_START <= addr && addr < BOOTROM_END { // TODO check flag
+    /// Advances the devices on the bus by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            if let Some((source, offset)) = self.dma.step() {
+                self.dma_value = self.load(source);
+                self.ppu.write_oam(offset, self.dma_value);
+            }
+        }
+        self.io_ports.tick(cycles);
+        self.ppu.tick(cycles);
+        self.cart.tick(cycles);
+    }
+
+    /// Finishes an instruction that took the given number of M-cycles,
+    /// advancing the devices by the cycles that weren't spent on bus
+    /// accesses. Returns the number of M-cycles spent on bus accesses.
+    pub fn finish_instruction(&mut self, cycles: u8) -> u8 {
+        let accesses = self.access_cycles;
+        self.access_cycles = 0;
+        self.tick(cycles.saturating_sub(accesses));
+        accesses
+    }
+
+    /// Every bus access takes one M-cycle. The devices are advanced first,
+    /// so that the access sees them as they are at that cycle.
+    fn tick_access(&mut self) {
+        self.tick(1);
+        self.access_cycles = self.access_cycles.saturating_add(1);
+    }
+
+    pub fn reset_divider(&mut self) {
+        self.io_ports.reset_divider();
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        self.io_ports.joypad()
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        self.io_ports.joypad_mut()
+    }
+
+    pub fn sound(&self) -> &SoundRegisters {
+        self.io_ports.sound()
+    }
+
+    pub fn sound_mut(&mut self) -> &mut SoundRegisters {
+        self.io_ports.sound_mut()
+    }
+
+    pub fn connect_link(&mut self, link: Box<dyn LinkCable>) {
+        self.io_ports.connect_link(link);
+    }
+
+    pub fn disconnect_link(&mut self) -> Option<Box<dyn LinkCable>> {
+        self.io_ports.disconnect_link()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cart
+    }
+
+    /// Whether the boot ROM is still mapped over the start of the cartridge
+    /// ROM. The boot ROM unmaps itself by writing to 0xFF50.
+    pub fn bootrom_mapped(&self) -> bool {
+        self.bootrom_mapped
+    }
+
+    pub fn interrupts(&self) -> &InterruptLine {
+        &self.interrupts
+    }
+
+    /// Returns the highest priority interrupt that is both requested and
+    /// enabled.
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        Interrupt::highest_priority(
+            self.interrupts.flags() & self.interrupt_enable)
+    }
+
+    pub fn read8(&mut self, addr: u16) -> u8 {
+        self.tick_access();
+        match self.dma_conflict(addr) {
+            Some(val) => val,
+            None => self.load(addr),
+        }
+    }
+
+    pub fn write8(&mut self, addr: u16, val: u8) {
+        self.tick_access();
+        if self.dma_conflict(addr).is_none() {
+            self.store(addr, val);
+        }
+    }
+
+    /// While OAM DMA runs, the CPU can't reach OAM, and reads from the bus
+    /// the transfer is using see the byte being copied. Returns the value a
+    /// read sees if the access conflicts with the transfer. Writes that
+    /// conflict are lost.
+    ///
+    /// IO and HRAM sit on the CPU's internal bus, so they stay reachable.
+    fn dma_conflict(&self, addr: u16) -> Option<u8> {
+        if !self.dma.active() {
+            return None;
+        }
+        let source = self.dma.source();
+        let is_vram = |addr| VRAM_START <= addr && addr < VRAM_END;
+        if addr >= IO_PORT_START {
+            None
+        } else if OAM_START <= addr {
+            Some(0xFF)
+        } else if is_vram(addr) == is_vram(source) {
+            Some(self.dma_value)
+        } else {
+            None
+        }
+    }
+
+    /// Reads from the bus without taking any time.
+    fn load(&self, addr: u16) -> u8 {
+        if self.bootrom_mapped && BOOTROM_START <= addr && addr < BOOTROM_END {
             self.bootrom[(addr - BOOTROM_START) as usize]
         } else if CARTRIDGE_ROM_START <= addr && addr < CARTRIDGE_ROM_END {
             self.cart.read8(addr)
         } else if VRAM_START <= addr && addr < VRAM_END {
-            self.vram[(addr - VRAM_START) as usize]
+            if self.ppu.vram_accessible() {
+                self.ppu.read_vram(addr - VRAM_START)
+            } else {
+                0xFF
+            }
+        } else if CARTRIDGE_RAM_START <= addr && addr < CARTRIDGE_RAM_END {
+            self.cart.read_ram(addr - CARTRIDGE_RAM_START)
         } else if WRAM_START <= addr && addr < WRAM_END {
             self.wram[(addr - WRAM_START) as usize]
+        } else if ECHO_RAM_START <= addr && addr < ECHO_RAM_END {
+            self.wram[(addr - ECHO_RAM_START) as usize]
+        } else if OAM_START <= addr && addr < OAM_END {
+            if self.ppu.oam_accessible() {
+                self.ppu.read_oam(addr - OAM_START)
+            } else {
+                0xFF
+            }
+        } else if UNUSABLE_START <= addr && addr < UNUSABLE_END {
+            // On monochrome hardware, the unusable region reads as 0xFF while
+            // the PPU has OAM locked, and 0x00 otherwise.
+            if self.ppu.oam_accessible() {
+                0x00
+            } else {
+                0xFF
+            }
+        } else if addr == BOOTROM_DISABLE {
+            0xFF
+        } else if addr == OAM_DMA {
+            self.dma.read()
+        } else if is_lcd_port(addr) {
+            self.ppu.read(addr.get_lower())
         } else if IO_PORT_START <= addr && addr < IO_PORT_END {
             self.io_ports.read(addr.get_lower())
+        } else if HRAM_START <= addr && addr < HRAM_END {
+            self.hram[(addr - HRAM_START) as usize]
+        } else if addr == INTERRUPT_ENABLE {
+            self.interrupt_enable
         } else {
-            panic!("SEGFAULT: bus.read_word({} (0x{:x}))", addr, addr);
+            unreachable!()
         }
     }
 
-    pub fn write8(&mut self, addr: u16, val: u8) {
-        if VRAM_START <= addr && addr < VRAM_END {
-            self.vram[(addr - VRAM_START) as usize] = val;
+    /// Writes to the bus without taking any time.
+    fn store(&mut self, addr: u16, val: u8) {
+        if CARTRIDGE_ROM_START <= addr && addr < CARTRIDGE_ROM_END {
+            self.cart.write8(addr, val);
+        } else if VRAM_START <= addr && addr < VRAM_END {
+            // Writes are dropped while the PPU has the bus.
+            if self.ppu.vram_accessible() {
+                self.ppu.write_vram(addr - VRAM_START, val);
+            }
+        } else if CARTRIDGE_RAM_START <= addr && addr < CARTRIDGE_RAM_END {
+            self.cart.write_ram(addr - CARTRIDGE_RAM_START, val);
         } else if WRAM_START <= addr && addr < WRAM_END {
             self.wram[(addr - WRAM_START) as usize] = val;
+        } else if ECHO_RAM_START <= addr && addr < ECHO_RAM_END {
+            self.wram[(addr - ECHO_RAM_START) as usize] = val;
+        } else if OAM_START <= addr && addr < OAM_END {
+            if self.ppu.oam_accessible() {
+                self.ppu.write_oam(addr - OAM_START, val);
+            }
+        } else if UNUSABLE_START <= addr && addr < UNUSABLE_END {
+            // Writes to the unusable region are ignored.
+        } else if addr == BOOTROM_DISABLE {
+            // Once unmapped, the boot ROM stays unmapped until reset.
+            if val != 0 {
+                self.bootrom_mapped = false;
+            }
+        } else if addr == OAM_DMA {
+            self.dma.start(val);
+        } else if is_lcd_port(addr) {
+            self.ppu.write(addr.get_lower(), val);
         } else if IO_PORT_START <= addr && addr < IO_PORT_END {
-            self.io_ports.write(addr.get_lower(), val)
+            self.io_ports.write(addr.get_lower(), val);
+        } else if HRAM_START <= addr && addr < HRAM_END {
+            self.hram[(addr - HRAM_START) as usize] = val;
+        } else if addr == INTERRUPT_ENABLE {
+            self.interrupt_enable = val;
         } else {
-            panic!("SEGFAULT: bus.write_word({} (0x{:x}), {})", addr, addr, val);
+            unreachable!()
         }
     }
 
-    pub fn read16(&self, addr: u16) -> u16 {
-        (self.read8(addr) as u16) + ((self.read8(addr+1) as u16) << 8)
+    pub fn read16(&mut self, addr: u16) -> u16 {
+        (self.read8(addr) as u16) +
+            ((self.read8(addr.wrapping_add(1)) as u16) << 8)
     }
 
     pub fn write16(&mut self, addr: u16, val: u16) {
         self.write8(addr, (val & 0xFF) as u8);
-        self.write8(addr+1, (val>>8 & 0xFF) as u8);
+        self.write8(addr.wrapping_add(1), (val>>8 & 0xFF) as u8);
     }
 }
 
 
+/// Whether an address is one of the PPU's ports. The OAM DMA port sits in the
+/// middle of them, but isn't part of the PPU.
+fn is_lcd_port(addr: u16) -> bool {
+    LCD_PORT_START <= addr && addr < LCD_PORT_END && addr != OAM_DMA
+}
+
+
 pub const BOOTROM_START: u16 = 0x0000;
 pub const BOOTROM_END: u16 = 0x0100;
+pub const BOOTROM_DISABLE: u16 = 0xFF50;
 
 pub const CARTRIDGE_ROM_START: u16 = 0x0000;
-pub const CARTRIDGE_ROM_END: u16 = 0x4000;
+pub const CARTRIDGE_ROM_END: u16 = 0x8000;
 
 pub const VRAM_START: u16 = 0x8000;
 pub const VRAM_END: u16 = 0xA000;
 
+pub const CARTRIDGE_RAM_START: u16 = 0xA000;
+pub const CARTRIDGE_RAM_END: u16 = 0xC000;
+
 pub const WRAM_START: u16 = 0xC000;
 pub const WRAM_END: u16 = 0xE000;
 
+/// Mirrors the first 7.5 KiB of work RAM.
+pub const ECHO_RAM_START: u16 = 0xE000;
+pub const ECHO_RAM_END: u16 = 0xFE00;
+
+pub const OAM_START: u16 = 0xFE00;
+pub const OAM_END: u16 = 0xFEA0;
+
+pub const UNUSABLE_START: u16 = 0xFEA0;
+pub const UNUSABLE_END: u16 = 0xFF00;
+
 pub const IO_PORT_START: u16 = 0xFF00;
 pub const IO_PORT_END: u16 = 0xFF80;
+
+pub const LCD_PORT_START: u16 = 0xFF40;
+pub const LCD_PORT_END: u16 = 0xFF4C;
+pub const OAM_DMA: u16 = 0xFF46;
+
+pub const HRAM_START: u16 = 0xFF80;
+pub const HRAM_END: u16 = 0xFFFF;
+
+pub const INTERRUPT_ENABLE: u16 = 0xFFFF;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mmu() -> MMU {
+        let cart = Cartridge::from_buffer(vec![0; 0x8000]).unwrap();
+        let mut mmu = MMU::new(cart);
+        mmu.write8(BOOTROM_DISABLE, 1);
+        mmu
+    }
+
+    /// Reads STAT until the PPU is in the given mode.
+    fn wait_for_mode(mmu: &mut MMU, mode: u8) {
+        while mmu.read8(0xFF41) & 0b11 != mode {}
+    }
+
+    #[test]
+    fn ppu_locks_vram_and_oam() {
+        let mut mmu = mmu();
+        mmu.write8(0xFF40, 0x91);
+
+        // During the OAM scan, only OAM is locked.
+        mmu.write8(0x8000, 0x42);
+        mmu.write8(0xFE00, 0x42);
+        assert_eq!(mmu.read8(0x8000), 0x42);
+        assert_eq!(mmu.read8(0xFE00), 0xFF);
+        assert_eq!(mmu.read8(0xFEA0), 0xFF);
+
+        wait_for_mode(&mut mmu, 3);
+        mmu.write8(0x8000, 0x43);
+        mmu.write8(0xFE00, 0x43);
+        assert_eq!(mmu.read8(0x8000), 0xFF);
+        assert_eq!(mmu.read8(0xFE00), 0xFF);
+        assert_eq!(mmu.read8(0xFEA0), 0xFF);
+
+        wait_for_mode(&mut mmu, 0);
+        assert_eq!(mmu.read8(0x8000), 0x42);
+        assert_eq!(mmu.read8(0xFE00), 0x00);
+        assert_eq!(mmu.read8(0xFEA0), 0x00);
+        mmu.write8(0xFE00, 0x44);
+        assert_eq!(mmu.read8(0xFE00), 0x44);
+    }
+
+    /// Fills the first pages of work RAM with bytes that identify their
+    /// address, and starts an OAM DMA transfer from the given page.
+    fn start_dma(page: u8) -> MMU {
+        let mut mmu = mmu();
+        for addr in 0xC000..0xC200 {
+            mmu.write8(addr, (addr as u8) ^ (addr >> 8) as u8);
+        }
+        mmu.write8(OAM_DMA, page);
+        mmu
+    }
+
+    #[test]
+    fn dma_starts_after_a_cycle() {
+        let mut mmu = start_dma(0xC0);
+        assert!(!mmu.dma.active());
+        mmu.tick(1);
+        assert!(!mmu.dma.active());
+        assert_eq!(mmu.ppu().read_oam(0), 0x00);
+        mmu.tick(1);
+        assert!(mmu.dma.active());
+        assert_eq!(mmu.ppu().read_oam(0), 0xC0);
+        assert_eq!(mmu.ppu().read_oam(1), 0x00);
+    }
+
+    #[test]
+    fn dma_takes_160_cycles() {
+        let mut mmu = start_dma(0xC0);
+        mmu.tick(1);
+        mmu.tick(159);
+        assert_eq!(mmu.ppu().read_oam(158), 0xC0 ^ 158);
+        assert_eq!(mmu.ppu().read_oam(159), 0x00);
+        mmu.tick(1);
+        assert_eq!(mmu.ppu().read_oam(159), 0xC0 ^ 159);
+        assert!(mmu.dma.active());
+        mmu.tick(1);
+        assert!(!mmu.dma.active());
+        assert_eq!(mmu.read8(0xFE00), 0xC0);
+    }
+
+    #[test]
+    fn dma_restart() {
+        let mut mmu = start_dma(0xC0);
+        mmu.tick(51);
+        mmu.write8(OAM_DMA, 0xC1);
+        // The old transfer keeps the bus until the new one starts.
+        mmu.tick(1);
+        assert!(mmu.dma.active());
+        assert_eq!(mmu.ppu().read_oam(0), 0xC0);
+        assert_eq!(mmu.ppu().read_oam(51), 0xC0 ^ 51);
+        mmu.tick(1);
+        assert_eq!(mmu.ppu().read_oam(0), 0xC1);
+        assert_eq!(mmu.ppu().read_oam(52), 0x00);
+
+        mmu.tick(159);
+        assert!(mmu.dma.active());
+        mmu.tick(1);
+        assert!(!mmu.dma.active());
+        for offset in 0..160 {
+            assert_eq!(mmu.ppu().read_oam(offset), 0xC1 ^ offset as u8);
+        }
+    }
+
+    #[test]
+    fn dma_from_echo_pages() {
+        // Pages from 0xE0 up read from work RAM.
+        let mut mmu = start_dma(0xE1);
+        mmu.tick(1 + 160);
+        assert_eq!(mmu.ppu().read_oam(0), 0xC1);
+        assert_eq!(mmu.ppu().read_oam(159), 0xC1 ^ 159);
+
+        let mut mmu = start_dma(0xFE);
+        mmu.write8(0xDE00, 0x42);
+        mmu.tick(160);
+        assert_eq!(mmu.ppu().read_oam(0), 0x42);
+    }
+
+    #[test]
+    fn dma_bus_conflicts() {
+        let mut mmu = start_dma(0xC0);
+        mmu.write8(0x8000, 0x55);
+        mmu.write8(0xFF80, 0x66);
+        mmu.tick(10);
+
+        // Reads from the external bus see the byte being copied.
+        let val = mmu.read8(0x0100);
+        assert_eq!(val, mmu.dma_value);
+        assert_eq!(val, mmu.ppu().read_oam(11));
+        assert_eq!(mmu.read8(0xC1FF), mmu.dma_value);
+        // Writes to it are lost.
+        mmu.write8(0xC1FF, 0x00);
+        // VRAM is on its own bus, and IO and HRAM are inside the CPU.
+        assert_eq!(mmu.read8(0x8000), 0x55);
+        assert_eq!(mmu.read8(0xFF80), 0x66);
+        assert_eq!(mmu.read8(OAM_DMA), 0xC0);
+        // OAM can't be reached at all.
+        assert_eq!(mmu.read8(0xFE00), 0xFF);
+
+        mmu.tick(160);
+        assert_eq!(mmu.read8(0xC1FF), 0xC1 ^ 0xFF);
+
+        // A transfer from VRAM blocks VRAM instead.
+        let mut mmu = start_dma(0x80);
+        mmu.tick(10);
+        assert_eq!(mmu.read8(0x9000), mmu.dma_value);
+        assert_eq!(mmu.read8(0xC042), 0xC0 ^ 0x42);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+
+use ppu::{shade, Ppu, Sprite, SCREEN_WIDTH, SPRITE_PENALTY_DOTS};
+use utils::BitOps;
+
+
+/// The state of the pixel FIFO renderer within the current scanline.
+#[derive(Debug, Default)]
+pub struct Fifo {
+    bg: VecDeque<u8>,
+    sprites: VecDeque<SpritePixel>,
+    fetcher: Fetcher,
+    /// The next pixel to output.
+    x: u8,
+    /// Pixels still to drop from the start of the line, for fine scrolling.
+    discard: u8,
+    /// Dots left to wait for a sprite fetch.
+    stall: u16,
+    /// The sprites on this line, sorted by x, and the next one to fetch.
+    line_sprites: Vec<Sprite>,
+    next_sprite: usize,
+    /// The background tile the last sprite fetch waited for.
+    last_sprite_tile: Option<u16>,
+    /// Whether the fetcher has switched to the window.
+    window: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct SpritePixel {
+    color: u8,
+    high_palette: bool,
+    behind_bg: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Fetcher {
+    step: FetchStep,
+    /// The dots spent in the current step.
+    dots: u8,
+    /// The tile column to fetch next, relative to the scroll position or
+    /// the left edge of the window.
+    tile_x: u8,
+    tile: u8,
+    low: u8,
+    high: u8,
+    /// The first fetch of every line is thrown away.
+    dummy: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FetchStep { Tile, DataLow, DataHigh, Push }
+
+impl Default for FetchStep {
+    fn default() -> Self { FetchStep::Tile }
+}
+
+impl Ppu {
+    /// Resets the renderer at the start of mode 3.
+    pub(super) fn start_fifo_line(&mut self) {
+        let mut sprites = self.sprites.clone();
+        sprites.sort_by_key(|sprite| sprite.x);
+        self.fifo = Fifo {
+            fetcher: Fetcher { dummy: true, ..Default::default() },
+            discard: self.scx % 8,
+            line_sprites: sprites,
+            ..Default::default()
+        };
+    }
+
+    /// Advances the renderer by one dot. Returns whether the line is done.
+    pub(super) fn step_fifo(&mut self) -> bool {
+        if self.fifo.x as usize == SCREEN_WIDTH {
+            if self.fifo.window {
+                self.window_line += 1;
+            }
+            return true;
+        }
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        self.check_window_trigger();
+        if self.fetch_sprite() {
+            return false;
+        }
+        self.step_fetcher();
+        self.shift_pixel();
+        false
+    }
+
+    /// Switches the fetcher to the window when the output reaches WX. The
+    /// comparison only happens as each pixel goes out, so moving WX behind
+    /// the current position, or enabling the window late, misses the
+    /// trigger for the rest of the line.
+    fn check_window_trigger(&mut self) {
+        if self.fifo.window || !self.window_triggered ||
+                !self.lcdc.get_bit(5) {
+            return;
+        }
+        let x = self.fifo.x as u16;
+        let wx = self.wx as u16;
+        if x + 7 == wx || (x == 0 && wx < 7) {
+            self.fifo.window = true;
+            self.fifo.bg.clear();
+            self.fifo.fetcher = Fetcher {
+                dummy: self.fifo.fetcher.dummy,
+                ..Default::default()
+            };
+            // With WX below 7, the window starts partly off screen.
+            if wx < 7 {
+                self.fifo.discard = 7 - wx as u8;
+            }
+        }
+    }
+
+    /// Starts fetching the next sprite if the output has reached it. The
+    /// fetch stalls the whole pipeline.
+    fn fetch_sprite(&mut self) -> bool {
+        if !self.lcdc.get_bit(1) || self.fifo.bg.is_empty() {
+            return false;
+        }
+        let sprite = match self.fifo.line_sprites.get(self.fifo.next_sprite) {
+            Some(&sprite) if sprite.x <= self.fifo.x as i16 => sprite,
+            _ => return false,
+        };
+        self.fifo.next_sprite += 1;
+
+        let mut penalty = SPRITE_PENALTY_DOTS;
+        let pixel = (sprite.x + self.scx as i16 + 8) as u16;
+        if self.fifo.last_sprite_tile != Some(pixel / 8) {
+            penalty += 5u16.saturating_sub(pixel % 8);
+            self.fifo.last_sprite_tile = Some(pixel / 8);
+        }
+        self.fifo.stall = penalty - 1;
+
+        // Sprite pixels only replace transparent ones, so sprites fetched
+        // earlier keep priority.
+        let x = self.fifo.x as i16;
+        let row = self.ly as i16 - sprite.y;
+        for i in 0..8 {
+            if sprite.x + i < x {
+                continue;
+            }
+            let pixel = SpritePixel {
+                color: self.sprite_pixel(&sprite, i, row),
+                high_palette: sprite.flags.get_bit(4),
+                behind_bg: sprite.flags.get_bit(7),
+            };
+            let index = (sprite.x + i - x) as usize;
+            if index == self.fifo.sprites.len() {
+                self.fifo.sprites.push_back(pixel);
+            } else if self.fifo.sprites[index].color == 0 {
+                self.fifo.sprites[index] = pixel;
+            }
+        }
+        true
+    }
+
+    /// Runs the background fetcher for a dot. Each step but the last takes
+    /// two dots. The last one waits until the FIFO is empty.
+    fn step_fetcher(&mut self) {
+        let mut fetcher = self.fifo.fetcher;
+        fetcher.dots += 1;
+        match fetcher.step {
+            FetchStep::Tile if fetcher.dots == 2 => {
+                fetcher.tile = self.fetch_tile(fetcher.tile_x);
+                fetcher.step = FetchStep::DataLow;
+                fetcher.dots = 0;
+            }
+            FetchStep::DataLow if fetcher.dots == 2 => {
+                fetcher.low = self.vram[self.tile_row_offset(fetcher.tile)];
+                fetcher.step = FetchStep::DataHigh;
+                fetcher.dots = 0;
+            }
+            FetchStep::DataHigh if fetcher.dots == 2 => {
+                let offset = self.tile_row_offset(fetcher.tile) + 1;
+                fetcher.high = self.vram[offset];
+                fetcher.step = FetchStep::Push;
+                fetcher.dots = 0;
+            }
+            _ => (),
+        }
+
+        if fetcher.step == FetchStep::Push && self.fifo.bg.is_empty() {
+            if fetcher.dummy {
+                fetcher.dummy = false;
+            } else {
+                for bit in (0..8).rev() {
+                    let color = ((fetcher.high >> bit) & 1) << 1 |
+                        ((fetcher.low >> bit) & 1);
+                    self.fifo.bg.push_back(color);
+                }
+                fetcher.tile_x = fetcher.tile_x.wrapping_add(1);
+            }
+            fetcher.step = FetchStep::Tile;
+            fetcher.dots = 0;
+        }
+        self.fifo.fetcher = fetcher;
+    }
+
+    /// Reads a tile number from the background or window map, using the
+    /// registers as they are now.
+    fn fetch_tile(&self, tile_x: u8) -> u8 {
+        let (high_map, x, y) = if self.fifo.window {
+            (self.lcdc.get_bit(6), tile_x, self.window_line)
+        } else {
+            (self.lcdc.get_bit(3), (self.scx / 8).wrapping_add(tile_x),
+             self.scy.wrapping_add(self.ly))
+        };
+        let map = if high_map { 0x1C00 } else { 0x1800 };
+        self.vram[map + (y as usize / 8) * 32 + (x as usize % 32)]
+    }
+
+    fn tile_row_offset(&self, tile: u8) -> usize {
+        let y = if self.fifo.window {
+            self.window_line
+        } else {
+            self.scy.wrapping_add(self.ly)
+        };
+        self.tile_data_offset(tile) + (y as usize % 8) * 2
+    }
+
+    /// Shifts a pixel out to the LCD, mixing in any sprite pixel with the
+    /// palettes as they are now.
+    fn shift_pixel(&mut self) {
+        let bg = match self.fifo.bg.pop_front() {
+            Some(bg) => bg,
+            None => return,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        // On monochrome hardware, LCDC bit 0 blanks both the background and
+        // the window.
+        let bg = if self.lcdc.get_bit(0) { bg } else { 0 };
+        let mut shade_out = shade(self.bgp, bg);
+        if let Some(sprite) = self.fifo.sprites.pop_front() {
+            if sprite.color != 0 && self.lcdc.get_bit(1) &&
+                    !(sprite.behind_bg && bg != 0) {
+                let palette = if sprite.high_palette {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                shade_out = shade(palette, sprite.color);
+            }
+        }
+
+        let x = self.fifo.x as usize;
+        self.framebuffer[self.ly as usize * SCREEN_WIDTH + x] = shade_out;
+        self.fifo.x += 1;
+    }
+}
@@ -0,0 +1,981 @@
+use interrupts::{Interrupt, InterruptLine};
+use utils::BitOps;
+
+use self::fifo::Fifo;
+
+mod fifo;
+mod scanline;
+
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+
+/// How the PPU draws each scanline.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    /// Draws each line at once when mode 3 ends. Mode 3 still takes as long
+    /// as on hardware, but register writes during it only take effect on
+    /// the next line. This is the default, and much cheaper.
+    Scanline,
+    /// Emulates the pixel FIFO and background fetcher dot by dot, so that
+    /// register writes during mode 3 take effect from the exact pixel.
+    Fifo,
+}
+
+impl Default for Renderer {
+    fn default() -> Self { Renderer::Scanline }
+}
+
+
+/// The picture processing unit. It owns video RAM and OAM, and draws a frame
+/// into its framebuffer as it scans out each line.
+#[derive(Debug)]
+pub struct Ppu {
+    vram: Vec<u8>,
+    oam: Vec<u8>,
+    framebuffer: Vec<u8>,
+    interrupts: InterruptLine,
+
+    lcdc: u8,
+    /// The writable interrupt enable bits of STAT.
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+
+    mode: Mode,
+    /// The dot within the current scanline.
+    dot: u16,
+    /// The length of mode 3 on the current scanline.
+    drawing_dots: u16,
+    /// The sprites on the current scanline, found during the OAM scan.
+    sprites: Vec<Sprite>,
+    /// The OR of all enabled STAT interrupt sources. The interrupt is only
+    /// requested when this goes high.
+    stat_line: bool,
+    renderer: Renderer,
+    /// The renderer drawing the current line. Changing renderers only takes
+    /// effect on the next line.
+    line_renderer: Renderer,
+    fifo: Fifo,
+    /// Whether LY has matched WY during this frame. The window can only
+    /// appear after that.
+    window_triggered: bool,
+    /// The line of the window to draw next. It only advances on scanlines
+    /// where the window is visible.
+    window_line: u8,
+    frames: u64,
+}
+
+impl Ppu {
+    pub fn new(interrupts: InterruptLine) -> Self {
+        Ppu {
+            vram: vec![0; VRAM_SIZE],
+            oam: vec![0; OAM_SIZE],
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            interrupts: interrupts,
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+            dot: 0,
+            drawing_dots: MIN_DRAWING_DOTS,
+            sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            stat_line: false,
+            renderer: Renderer::Scanline,
+            line_renderer: Renderer::Scanline,
+            fifo: Fifo::default(),
+            window_triggered: false,
+            window_line: 0,
+            frames: 0,
+        }
+    }
+
+    /// The last drawn frame, as one shade per pixel in row-major order. Shade
+    /// 0 is white and shade 3 is black.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    /// The number of frames completed since power on.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Advances the PPU by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        for _ in 0..(cycles as u16 * DOTS_PER_CYCLE) {
+            self.step_dot();
+        }
+    }
+
+    fn step_dot(&mut self) {
+        self.dot += 1;
+        if self.mode == Mode::OamScan && self.dot == OAM_SCAN_DOTS {
+            self.scan_oam();
+            if self.wy == self.ly {
+                self.window_triggered = true;
+            }
+            self.line_renderer = self.renderer;
+            match self.line_renderer {
+                Renderer::Scanline =>
+                    self.drawing_dots = self.drawing_length(),
+                Renderer::Fifo => self.start_fifo_line(),
+            }
+            self.mode = Mode::Drawing;
+        } else if self.mode == Mode::Drawing {
+            let done = match self.line_renderer {
+                Renderer::Scanline =>
+                    self.dot == OAM_SCAN_DOTS + self.drawing_dots,
+                Renderer::Fifo => self.step_fifo(),
+            };
+            if done {
+                self.drawing_dots = self.dot - OAM_SCAN_DOTS;
+                self.mode = Mode::HBlank;
+                if self.line_renderer == Renderer::Scanline {
+                    self.render_scanline();
+                }
+            }
+        }
+
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly += 1;
+            if self.ly as usize == SCREEN_HEIGHT {
+                self.mode = Mode::VBlank;
+                self.frames += 1;
+                self.interrupts.request(Interrupt::VBlank);
+            } else if self.ly == LINES_PER_FRAME {
+                self.ly = 0;
+                self.window_line = 0;
+                self.window_triggered = false;
+                self.mode = Mode::OamScan;
+            } else if self.mode != Mode::VBlank {
+                self.mode = Mode::OamScan;
+            }
+        }
+        self.update_stat_line();
+    }
+
+    /// Finds the first ten sprites in OAM that overlap the current line.
+    fn scan_oam(&mut self) {
+        let ly = self.ly as i16;
+        let height = self.sprite_height();
+        self.sprites.clear();
+        for entry in self.oam.chunks(4) {
+            let sprite = Sprite {
+                y: entry[0] as i16 - 16,
+                x: entry[1] as i16 - 8,
+                tile: entry[2],
+                flags: entry[3],
+            };
+            if sprite.y <= ly && ly < sprite.y + height {
+                self.sprites.push(sprite);
+                if self.sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.lcdc.get_bit(2) { 16 } else { 8 }
+    }
+
+    /// Returns the color index of a sprite pixel, relative to the sprite's
+    /// top left corner.
+    fn sprite_pixel(&self, sprite: &Sprite, x: i16, y: i16) -> u8 {
+        let height = self.sprite_height();
+        let x = if sprite.flags.get_bit(5) { 7 - x } else { x };
+        let y = if sprite.flags.get_bit(6) { height - 1 - y } else { y };
+        let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        self.tile_pixel(tile as usize * 16, x as u8, y as u8)
+    }
+
+    /// Returns the VRAM offset of a background or window tile, using the
+    /// addressing mode selected by LCDC bit 4.
+    fn tile_data_offset(&self, tile: u8) -> usize {
+        if self.lcdc.get_bit(4) {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as isize) * 16) as usize
+        }
+    }
+
+    fn tile_pixel(&self, offset: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[offset + y as usize * 2];
+        let high = self.vram[offset + y as usize * 2 + 1];
+        let bit = 7 - x;
+        ((high >> bit) & 1) << 1 | ((low >> bit) & 1)
+    }
+
+    /// Returns the length of mode 3 on the current line. The fetcher stalls
+    /// to discard the fine scroll, to restart for the window, and to fetch
+    /// each sprite.
+    fn drawing_length(&self) -> u16 {
+        let mut dots = MIN_DRAWING_DOTS + (self.scx % 8) as u16;
+        if self.window_visible() {
+            dots += WINDOW_PENALTY_DOTS;
+        }
+
+        if self.lcdc.get_bit(1) {
+            let mut sprites = self.sprites.clone();
+            sprites.sort_by_key(|sprite| sprite.x);
+            let mut last_tile = None;
+            for sprite in sprites.iter()
+                    .filter(|sprite| sprite.x < SCREEN_WIDTH as i16) {
+                dots += SPRITE_PENALTY_DOTS;
+                // The first sprite in each background tile also waits for
+                // the background fetch of that tile to finish.
+                let pixel = (sprite.x + self.scx as i16 + 8) as u16;
+                let tile = pixel / 8;
+                if last_tile != Some(tile) {
+                    dots += 5u16.saturating_sub(pixel % 8);
+                    last_tile = Some(tile);
+                }
+            }
+        }
+        dots
+    }
+
+    /// Whether the window covers part of the current line. Like the FIFO,
+    /// this goes by whether LY has matched WY this frame, so changing WY
+    /// afterwards doesn't hide the window again.
+    fn window_visible(&self) -> bool {
+        self.lcdc.get_bit(0) && self.lcdc.get_bit(5) && self.window_triggered &&
+            self.wx <= 166
+    }
+
+    fn coincidence(&self) -> bool {
+        self.ly == self.lyc
+    }
+
+    /// Requests a STAT interrupt when any enabled source becomes active while
+    /// no other one already was.
+    fn update_stat_line(&mut self) {
+        let line = self.lcd_enabled() && (
+            (self.stat.get_bit(6) && self.coincidence()) ||
+            (self.stat.get_bit(5) && self.mode == Mode::OamScan) ||
+            (self.stat.get_bit(4) && self.mode == Mode::VBlank) ||
+            (self.stat.get_bit(3) && self.mode == Mode::HBlank));
+        if line && !self.stat_line {
+            self.interrupts.request(Interrupt::LcdStat);
+        }
+        self.stat_line = line;
+    }
+
+    /// Whether the CPU can access VRAM. It is locked while the PPU draws.
+    pub fn vram_accessible(&self) -> bool {
+        self.mode != Mode::Drawing
+    }
+
+    /// Whether the CPU can access OAM. It is locked during the OAM scan and
+    /// while the PPU draws.
+    pub fn oam_accessible(&self) -> bool {
+        self.mode != Mode::OamScan && self.mode != Mode::Drawing
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.lcdc.get_bit(7)
+    }
+
+    pub fn read_vram(&self, offset: u16) -> u8 {
+        self.vram[offset as usize]
+    }
+
+    pub fn write_vram(&mut self, offset: u16, val: u8) {
+        self.vram[offset as usize] = val;
+    }
+
+    pub fn read_oam(&self, offset: u16) -> u8 {
+        self.oam[offset as usize]
+    }
+
+    pub fn write_oam(&mut self, offset: u16, val: u8) {
+        self.oam[offset as usize] = val;
+    }
+
+    /// Reads from one of the LCD ports, 0xFF40-0xFF4B.
+    pub fn read(&self, port: u8) -> u8 {
+        match port {
+            0x40 => self.lcdc,
+            0x41 => {
+                let mut stat = 0x80 | self.stat | self.mode as u8;
+                stat.set_bit(2, self.coincidence());
+                stat
+            }
+            0x42 => self.scy,
+            0x43 => self.scx,
+            0x44 => self.ly,
+            0x45 => self.lyc,
+            0x47 => self.bgp,
+            0x48 => self.obp0,
+            0x49 => self.obp1,
+            0x4A => self.wy,
+            0x4B => self.wx,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Writes to one of the LCD ports, 0xFF40-0xFF4B.
+    pub fn write(&mut self, port: u8, val: u8) {
+        match port {
+            0x40 => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = val;
+                if was_enabled && !self.lcd_enabled() {
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                    self.mode = Mode::HBlank;
+                } else if !was_enabled && self.lcd_enabled() {
+                    self.mode = Mode::OamScan;
+                }
+                self.update_stat_line();
+            }
+            0x41 => {
+                self.stat = val & STAT_ENABLE_MASK;
+                self.update_stat_line();
+            }
+            0x42 => self.scy = val,
+            0x43 => self.scx = val,
+            // LY is read-only.
+            0x44 => (),
+            0x45 => {
+                self.lyc = val;
+                self.update_stat_line();
+            }
+            0x47 => self.bgp = val,
+            0x48 => self.obp0 = val,
+            0x49 => self.obp1 = val,
+            0x4A => self.wy = val,
+            0x4B => self.wx = val,
+            _ => unreachable!(),
+        }
+    }
+}
+
+
+/// Maps a color index through a DMG palette register.
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
+
+/// An object attribute entry from OAM, in screen coordinates.
+#[derive(Copy, Clone, Debug)]
+struct Sprite {
+    y: i16,
+    x: i16,
+    tile: u8,
+    flags: u8,
+}
+
+
+/// The PPU mode, as reported in the low bits of STAT.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+
+const VRAM_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xA0;
+
+const DOTS_PER_CYCLE: u16 = 4;
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const MIN_DRAWING_DOTS: u16 = 172;
+const WINDOW_PENALTY_DOTS: u16 = 6;
+const SPRITE_PENALTY_DOTS: u16 = 6;
+const MAX_SPRITES_PER_LINE: usize = 10;
+const LINES_PER_FRAME: u8 = 154;
+
+const STAT_ENABLE_MASK: u8 = 0b0111_1000;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The LCD on, with the background using the tile data at 0x8000.
+    const LCDC_BG: u8 = 0x91;
+    const LCDC_SPRITES: u8 = 0x02;
+    /// The window enabled, using the tile map at 0x9C00.
+    const LCDC_WINDOW: u8 = 0x60;
+
+    /// Turns on the LCD with the given LCDC, after setting the palettes to
+    /// map each color to the shade of the same number.
+    fn ppu(lcdc: u8) -> (Ppu, InterruptLine) {
+        let interrupts = InterruptLine::new();
+        let mut ppu = Ppu::new(interrupts.clone());
+        for port in 0x47..0x4A {
+            ppu.write(port, 0xE4);
+        }
+        ppu.write(0x40, lcdc);
+        (ppu, interrupts)
+    }
+
+    fn set_tile_row(ppu: &mut Ppu, tile: u8, row: u8, low: u8, high: u8) {
+        let offset = tile as u16 * 16 + row as u16 * 2;
+        ppu.write_vram(offset, low);
+        ppu.write_vram(offset + 1, high);
+    }
+
+    fn fill_tile(ppu: &mut Ppu, tile: u8, color: u8) {
+        let low = if color.get_bit(0) { 0xFF } else { 0x00 };
+        let high = if color.get_bit(1) { 0xFF } else { 0x00 };
+        for row in 0..8 {
+            set_tile_row(ppu, tile, row, low, high);
+        }
+    }
+
+    fn fill_map(ppu: &mut Ppu, map: u16, tile: u8) {
+        for i in 0..0x400 {
+            ppu.write_vram(map + i, tile);
+        }
+    }
+
+    /// Adds a sprite at the given screen position.
+    fn set_sprite(ppu: &mut Ppu, index: u16, x: i16, y: i16, tile: u8,
+                  flags: u8) {
+        let entry = [(y + 16) as u8, (x + 8) as u8, tile, flags];
+        for (i, &byte) in entry.iter().enumerate() {
+            ppu.write_oam(index * 4 + i as u16, byte);
+        }
+    }
+
+    fn run_frame(ppu: &mut Ppu) {
+        let frame = ppu.frames();
+        while ppu.frames() == frame {
+            ppu.tick(1);
+        }
+    }
+
+    fn run_lines(ppu: &mut Ppu, lines: u16) {
+        for _ in 0..lines {
+            ppu.tick((DOTS_PER_LINE / DOTS_PER_CYCLE) as u8);
+        }
+    }
+
+    fn stat_requested(interrupts: &InterruptLine) -> bool {
+        interrupts.flags().get_bit(Interrupt::LcdStat.bit())
+    }
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> u8 {
+        ppu.framebuffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn background_scrolls() {
+        let (mut ppu, _) = ppu(LCDC_BG);
+        fill_tile(&mut ppu, 1, 3);
+        ppu.write_vram(0x1800, 1);
+        ppu.write(0x42, 2);
+        ppu.write(0x43, 4);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 3, 5), 3);
+        assert_eq!(pixel(&ppu, 4, 5), 0);
+        assert_eq!(pixel(&ppu, 3, 6), 0);
+
+        // The map wraps around at 256 pixels.
+        ppu.write(0x42, 0);
+        ppu.write(0x43, 252);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 3, 0), 0);
+        assert_eq!(pixel(&ppu, 4, 0), 3);
+        assert_eq!(pixel(&ppu, 11, 7), 3);
+        assert_eq!(pixel(&ppu, 12, 7), 0);
+    }
+
+    #[test]
+    fn background_palette() {
+        let (mut ppu, _) = ppu(LCDC_BG);
+        fill_tile(&mut ppu, 1, 1);
+        ppu.write_vram(0x1800, 1);
+        ppu.write(0x47, 0b00_01_11_10);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 3);
+        assert_eq!(pixel(&ppu, 8, 0), 2);
+
+        // LCDC bit 0 blanks the background to color 0.
+        ppu.write(0x40, LCDC_BG & !0x01);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 2);
+    }
+
+    #[test]
+    fn window() {
+        let (mut ppu, _) = ppu(LCDC_BG | LCDC_WINDOW);
+        // Only the top row of the window's tiles is drawn.
+        set_tile_row(&mut ppu, 1, 0, 0xFF, 0xFF);
+        fill_map(&mut ppu, 0x1C00, 1);
+        ppu.write(0x4A, 10);
+        ppu.write(0x4B, 27);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 19, 10), 0);
+        assert_eq!(pixel(&ppu, 20, 10), 3);
+        assert_eq!(pixel(&ppu, 159, 10), 3);
+        assert_eq!(pixel(&ppu, 20, 9), 0);
+        assert_eq!(pixel(&ppu, 20, 11), 0);
+        // The window starts from its own first line, not from LY.
+        assert_eq!(pixel(&ppu, 20, 18), 3);
+
+        // It isn't drawn when the background is disabled.
+        ppu.write(0x40, LCDC_BG & !0x01 | LCDC_WINDOW);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 20, 10), 0);
+    }
+
+    #[test]
+    fn sprites() {
+        let (mut ppu, _) = ppu(LCDC_BG | LCDC_SPRITES);
+        fill_tile(&mut ppu, 1, 3);
+        fill_tile(&mut ppu, 2, 1);
+        // A tile with only its top left pixel set.
+        set_tile_row(&mut ppu, 3, 0, 0x80, 0x80);
+        ppu.write_vram(0x1800 + 32 + 5, 1);
+        ppu.write(0x49, 0b00_01_10_11);
+
+        set_sprite(&mut ppu, 0, 8, 8, 2, 0x00);
+        set_sprite(&mut ppu, 1, 24, 8, 2, 0x10);
+        // Behind the background, which only lets color 0 through.
+        set_sprite(&mut ppu, 2, 40, 8, 2, 0x80);
+        set_sprite(&mut ppu, 3, 56, 8, 2, 0x80);
+        // Flipped both ways.
+        set_sprite(&mut ppu, 4, 72, 8, 3, 0x60);
+        run_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 7, 8), 0);
+        assert_eq!(pixel(&ppu, 8, 8), 1);
+        assert_eq!(pixel(&ppu, 15, 15), 1);
+        assert_eq!(pixel(&ppu, 16, 15), 0);
+        assert_eq!(pixel(&ppu, 8, 16), 0);
+        assert_eq!(pixel(&ppu, 24, 8), 2);
+        assert_eq!(pixel(&ppu, 40, 8), 3);
+        assert_eq!(pixel(&ppu, 56, 8), 1);
+        assert_eq!(pixel(&ppu, 72, 8), 0);
+        assert_eq!(pixel(&ppu, 79, 15), 3);
+
+        // LCDC bit 1 hides them.
+        ppu.write(0x40, LCDC_BG);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 8, 8), 0);
+    }
+
+    #[test]
+    fn tall_sprites() {
+        let (mut ppu, _) = ppu(LCDC_BG | LCDC_SPRITES | 0x04);
+        fill_tile(&mut ppu, 4, 1);
+        fill_tile(&mut ppu, 5, 2);
+        // The lowest bit of the tile number is ignored.
+        set_sprite(&mut ppu, 0, 8, 8, 5, 0x00);
+        set_sprite(&mut ppu, 1, 24, 8, 5, 0x40);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 8, 8), 1);
+        assert_eq!(pixel(&ppu, 8, 23), 2);
+        assert_eq!(pixel(&ppu, 8, 24), 0);
+        assert_eq!(pixel(&ppu, 24, 8), 2);
+        assert_eq!(pixel(&ppu, 24, 23), 1);
+    }
+
+    #[test]
+    fn sprite_priority() {
+        let (mut ppu, _) = ppu(LCDC_BG | LCDC_SPRITES);
+        fill_tile(&mut ppu, 1, 1);
+        // Color 2 on the left half, transparent on the right.
+        for row in 0..8 {
+            set_tile_row(&mut ppu, 2, row, 0x00, 0xF0);
+        }
+        // The sprite further left is on top, whatever its OAM order, but
+        // its transparent pixels show the one below.
+        set_sprite(&mut ppu, 0, 10, 0, 1, 0x00);
+        set_sprite(&mut ppu, 1, 8, 0, 2, 0x00);
+        // At the same position, the first in OAM is on top.
+        set_sprite(&mut ppu, 2, 40, 0, 2, 0x00);
+        set_sprite(&mut ppu, 3, 40, 0, 1, 0x00);
+        run_frame(&mut ppu);
+
+        let colors: Vec<_> = (8..18).map(|x| pixel(&ppu, x, 0)).collect();
+        assert_eq!(colors, [2, 2, 2, 2, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(pixel(&ppu, 40, 0), 2);
+        assert_eq!(pixel(&ppu, 44, 0), 1);
+    }
+
+    #[test]
+    fn ten_sprites_per_line() {
+        let (mut ppu, _) = ppu(LCDC_BG | LCDC_SPRITES);
+        fill_tile(&mut ppu, 1, 3);
+        for i in 0..11 {
+            set_sprite(&mut ppu, i, i as i16 * 10, 0, 1, 0x00);
+        }
+        // Sprites off the left edge still use up a slot.
+        for i in 11..21 {
+            set_sprite(&mut ppu, i, -8, 20, 1, 0x00);
+        }
+        set_sprite(&mut ppu, 21, 50, 24, 1, 0x00);
+        run_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 90, 0), 3);
+        assert_eq!(pixel(&ppu, 100, 0), 0);
+        assert_eq!(pixel(&ppu, 50, 27), 0);
+        // Below the off screen sprites, there is a free slot.
+        assert_eq!(pixel(&ppu, 50, 28), 3);
+    }
+
+    #[test]
+    fn modes() {
+        let (mut ppu, interrupts) = ppu(LCDC_BG);
+        // LY matches LYC, which sets bit 2.
+        assert_eq!(ppu.read(0x41), 0x86);
+        ppu.tick(19);
+        assert_eq!(ppu.read(0x41) & 0b11, 2);
+        ppu.tick(1);
+        assert_eq!(ppu.read(0x41) & 0b11, 3);
+        ppu.tick(42);
+        assert_eq!(ppu.read(0x41) & 0b11, 3);
+        ppu.tick(1);
+        assert_eq!(ppu.read(0x41) & 0b11, 0);
+        ppu.tick(50);
+        assert_eq!(ppu.read(0x41) & 0b11, 0);
+        assert_eq!(ppu.read(0x44), 0);
+        ppu.tick(1);
+        assert_eq!(ppu.read(0x41) & 0b11, 2);
+        assert_eq!(ppu.read(0x44), 1);
+
+        run_lines(&mut ppu, 142);
+        assert_eq!(ppu.read(0x44), 143);
+        assert_eq!(interrupts.flags(), 0);
+        run_lines(&mut ppu, 1);
+        assert_eq!(ppu.read(0x44), 144);
+        assert_eq!(ppu.read(0x41) & 0b11, 1);
+        assert_eq!(interrupts.flags(), 1 << Interrupt::VBlank.bit());
+        assert_eq!(ppu.frames(), 1);
+        run_lines(&mut ppu, 9);
+        assert_eq!(ppu.read(0x44), 153);
+        assert_eq!(ppu.read(0x41) & 0b11, 1);
+        run_lines(&mut ppu, 1);
+        assert_eq!(ppu.read(0x44), 0);
+        assert_eq!(ppu.read(0x41) & 0b11, 2);
+
+        // Turning the LCD off resets it, and stops it.
+        ppu.tick(30);
+        ppu.write(0x40, 0x00);
+        assert_eq!(ppu.read(0x41) & 0b11, 0);
+        run_lines(&mut ppu, 2);
+        assert_eq!(ppu.read(0x44), 0);
+        assert_eq!(ppu.read(0x41) & 0b11, 0);
+    }
+
+    #[test]
+    fn vram_and_oam_locking() {
+        let (mut ppu, _) = ppu(LCDC_BG);
+        assert!(ppu.vram_accessible());
+        assert!(!ppu.oam_accessible());
+        ppu.tick(20);
+        assert!(!ppu.vram_accessible());
+        assert!(!ppu.oam_accessible());
+        ppu.tick(43);
+        assert!(ppu.vram_accessible());
+        assert!(ppu.oam_accessible());
+        run_lines(&mut ppu, 144);
+        assert!(ppu.vram_accessible());
+        assert!(ppu.oam_accessible());
+    }
+
+    /// Runs the first line with the given setup, and returns the length of
+    /// its mode 3.
+    fn drawing_dots<F: FnOnce(&mut Ppu)>(lcdc: u8, setup: F) -> u16 {
+        let (mut ppu, _) = ppu(lcdc);
+        setup(&mut ppu);
+        run_lines(&mut ppu, 1);
+        ppu.drawing_dots
+    }
+
+    #[test]
+    fn drawing_length() {
+        let lcdc = LCDC_BG | LCDC_SPRITES;
+        assert_eq!(drawing_dots(lcdc, |_| ()), 172);
+        // The fine scroll is discarded a pixel per dot.
+        assert_eq!(drawing_dots(lcdc, |ppu| ppu.write(0x43, 3)), 175);
+        assert_eq!(drawing_dots(lcdc, |ppu| ppu.write(0x43, 8)), 172);
+
+        // Starting the window restarts the fetcher.
+        let lcdc = lcdc | LCDC_WINDOW;
+        assert_eq!(drawing_dots(lcdc, |ppu| ppu.write(0x4B, 7)), 178);
+        assert_eq!(drawing_dots(lcdc, |ppu| ppu.write(0x4B, 167)), 172);
+        assert_eq!(drawing_dots(lcdc, |ppu| ppu.write(0x4A, 1)), 172);
+
+        // Each sprite takes six dots, and the first in a tile waits for up
+        // to five more for the background fetch.
+        let lcdc = LCDC_BG | LCDC_SPRITES;
+        let sprites = |positions: &'static [i16]| move |ppu: &mut Ppu| {
+            for (i, &x) in positions.iter().enumerate() {
+                set_sprite(ppu, i as u16, x, 0, 0, 0x00);
+            }
+        };
+        assert_eq!(drawing_dots(lcdc, sprites(&[0])), 183);
+        assert_eq!(drawing_dots(lcdc, sprites(&[-8])), 183);
+        assert_eq!(drawing_dots(lcdc, sprites(&[0, 4])), 189);
+        assert_eq!(drawing_dots(lcdc, sprites(&[0, 8])), 194);
+        assert_eq!(drawing_dots(lcdc, sprites(&[3])), 180);
+        assert_eq!(drawing_dots(lcdc, sprites(&[5])), 178);
+        assert_eq!(drawing_dots(lcdc, sprites(&[160])), 172);
+        assert_eq!(drawing_dots(LCDC_BG, sprites(&[0])), 172);
+        // Only the first ten on the line are fetched.
+        let eleven = &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(drawing_dots(lcdc, sprites(eleven)), 172 + 11 + 9 * 6);
+
+        // The sprite's tile depends on the fine scroll.
+        assert_eq!(drawing_dots(lcdc | LCDC_WINDOW, |ppu| {
+            ppu.write(0x43, 3);
+            ppu.write(0x4B, 7);
+            sprites(&[0])(ppu);
+        }), 172 + 3 + 6 + 6 + 2);
+    }
+
+    #[test]
+    fn mode_3_ends_after_drawing_length() {
+        let (mut ppu, _) = ppu(LCDC_BG);
+        ppu.write(0x43, 4);
+        ppu.tick(20 + 43);
+        assert_eq!(ppu.read(0x41) & 0b11, 3);
+        ppu.tick(1);
+        assert_eq!(ppu.read(0x41) & 0b11, 0);
+    }
+
+    #[test]
+    fn stat_mode_interrupts() {
+        let (mut ppu, interrupts) = ppu(LCDC_BG);
+        ppu.write(0x41, 0x08);
+        ppu.tick(62);
+        assert!(!stat_requested(&interrupts));
+        ppu.tick(1);
+        assert!(stat_requested(&interrupts));
+
+        // The OAM source would go high as HBlank ends, but the line is
+        // already high, so there is no new interrupt.
+        ppu.write(0x41, 0x28);
+        interrupts.set_flags(0);
+        ppu.tick(51);
+        assert_eq!(ppu.read(0x44), 1);
+        assert!(!stat_requested(&interrupts));
+
+        // Without the HBlank source, the line drops in between.
+        ppu.write(0x41, 0x20);
+        run_lines(&mut ppu, 1);
+        assert!(stat_requested(&interrupts));
+
+        ppu.write(0x41, 0x10);
+        interrupts.set_flags(0);
+        run_lines(&mut ppu, 141);
+        assert!(!stat_requested(&interrupts));
+        run_lines(&mut ppu, 1);
+        assert_eq!(ppu.read(0x44), 144);
+        assert!(stat_requested(&interrupts));
+    }
+
+    #[test]
+    fn lyc_interrupt() {
+        let (mut ppu, interrupts) = ppu(LCDC_BG);
+        ppu.write(0x45, 3);
+        ppu.write(0x41, 0x40);
+        run_lines(&mut ppu, 2);
+        assert!(!stat_requested(&interrupts));
+        assert!(!ppu.read(0x41).get_bit(2));
+        run_lines(&mut ppu, 1);
+        assert!(stat_requested(&interrupts));
+        assert!(ppu.read(0x41).get_bit(2));
+
+        // While the line is high, HBlank doesn't trigger another interrupt.
+        ppu.write(0x41, 0x48);
+        interrupts.set_flags(0);
+        ppu.tick(63);
+        assert!(!stat_requested(&interrupts));
+        ppu.tick(51);
+        assert!(!ppu.read(0x41).get_bit(2));
+
+        // Writing LYC compares it right away.
+        ppu.write(0x41, 0x40);
+        ppu.write(0x45, 4);
+        assert!(stat_requested(&interrupts));
+    }
+
+    /// Draws a frame with each renderer, setting up the PPU the same way.
+    fn render_both<F: Fn(&mut Ppu)>(lcdc: u8, setup: F) -> [Ppu; 2] {
+        let mut renderers = [Renderer::Scanline, Renderer::Fifo].iter()
+            .map(|&renderer| {
+                let (mut ppu, _) = ppu(lcdc);
+                ppu.set_renderer(renderer);
+                setup(&mut ppu);
+                run_frame(&mut ppu);
+                ppu
+            });
+        [renderers.next().unwrap(), renderers.next().unwrap()]
+    }
+
+    #[test]
+    fn window_position_is_latched() {
+        // Moving WY after LY has matched it doesn't hide the window.
+        let lcdc = LCDC_BG | LCDC_WINDOW;
+        for ppu in render_both(lcdc, |ppu| {
+            fill_tile(ppu, 1, 3);
+            fill_map(ppu, 0x1C00, 1);
+            ppu.write(0x4A, 10);
+            ppu.write(0x4B, 7);
+            run_lines(ppu, 20);
+            ppu.write(0x4A, 100);
+        }).iter() {
+            assert_eq!(pixel(ppu, 0, 9), 0);
+            assert_eq!(pixel(ppu, 0, 10), 3);
+            assert_eq!(pixel(ppu, 0, 143), 3);
+        }
+
+        // And moving it to a line that has passed doesn't show it.
+        for ppu in render_both(lcdc, |ppu| {
+            fill_tile(ppu, 1, 3);
+            fill_map(ppu, 0x1C00, 1);
+            ppu.write(0x4A, 100);
+            ppu.write(0x4B, 7);
+            run_lines(ppu, 20);
+            ppu.write(0x4A, 10);
+        }).iter() {
+            assert_eq!(pixel(ppu, 0, 143), 0);
+        }
+        let dots = drawing_dots(lcdc, |ppu| {
+            ppu.write(0x4A, 100);
+            run_lines(ppu, 20);
+            ppu.write(0x4A, 10);
+        });
+        assert_eq!(dots, 172);
+    }
+
+    #[test]
+    fn fifo_matches_scanline() {
+        let lcdc = LCDC_BG | LCDC_SPRITES | LCDC_WINDOW;
+        let [scanline, fifo] = render_both(lcdc, |ppu| {
+            for tile in 0..4u8 {
+                // Each row of the tile is a different pattern.
+                for row in 0..8 {
+                    let low = tile.wrapping_mul(37).wrapping_add(row * 11);
+                    set_tile_row(ppu, tile, row, low, low.rotate_left(3));
+                }
+            }
+            for i in 0..0x800 {
+                ppu.write_vram(0x1800 + i, (i * 7 % 4) as u8);
+            }
+            ppu.write(0x42, 5);
+            ppu.write(0x43, 3);
+            ppu.write(0x4A, 60);
+            ppu.write(0x4B, 90);
+            for i in 0..12 {
+                set_sprite(ppu, i, i as i16 * 13 - 4, i as i16 * 9, i as u8,
+                           (i as u8).wrapping_mul(0x30));
+            }
+        });
+        assert!(scanline.framebuffer() == fifo.framebuffer());
+    }
+
+    #[test]
+    fn fifo_applies_mid_line_writes() {
+        let (mut fifo, _) = ppu(LCDC_BG);
+        fifo.set_renderer(Renderer::Fifo);
+        fill_tile(&mut fifo, 1, 3);
+        // Stripes of alternating tiles on the first row of the map.
+        for x in 0..32 {
+            fifo.write_vram(0x1800 + x, x as u8 % 2);
+        }
+        fifo.tick(20 + 20);
+        fifo.write(0x43, 8);
+        fifo.write(0x47, 0b00_01_10_01);
+        run_lines(&mut fifo, 1);
+
+        // The left of the line is drawn as it was, and the right with the
+        // new scroll and palette.
+        assert_eq!(pixel(&fifo, 0, 0), 0);
+        assert_eq!(pixel(&fifo, 8, 0), 3);
+        assert_eq!(pixel(&fifo, 120, 0), 1);
+        assert_eq!(pixel(&fifo, 128, 0), 0);
+        let first_new = (0..160).find(|&x| pixel(&fifo, x, 0) == 1).unwrap();
+        assert!((first_new..160).all(|x| pixel(&fifo, x, 0) != 3));
+
+        // The scanline renderer uses the registers as they are at the end.
+        let (mut scanline, _) = ppu(LCDC_BG);
+        fill_tile(&mut scanline, 1, 3);
+        for x in 0..32 {
+            scanline.write_vram(0x1800 + x, x as u8 % 2);
+        }
+        scanline.tick(20 + 20);
+        scanline.write(0x43, 8);
+        run_lines(&mut scanline, 1);
+        assert_eq!(pixel(&scanline, 0, 0), 3);
+    }
+
+    #[test]
+    fn fifo_sprite_stalls() {
+        let lcdc = LCDC_BG | LCDC_SPRITES;
+        let fifo_dots = |scx: u8, positions: &[i16]| {
+            drawing_dots(lcdc, |ppu| {
+                ppu.set_renderer(Renderer::Fifo);
+                ppu.write(0x43, scx);
+                for (i, &x) in positions.iter().enumerate() {
+                    set_sprite(ppu, i as u16, x, 0, 0, 0x00);
+                }
+            })
+        };
+        assert_eq!(fifo_dots(0, &[]), 172);
+        assert_eq!(fifo_dots(3, &[]), 175);
+        assert_eq!(fifo_dots(0, &[0]), 183);
+        assert_eq!(fifo_dots(0, &[0, 4]), 189);
+        assert_eq!(fifo_dots(0, &[0, 8]), 194);
+        assert_eq!(fifo_dots(0, &[3]), 180);
+        assert_eq!(fifo_dots(0, &[5]), 178);
+        assert_eq!(fifo_dots(3, &[0]), 172 + 3 + 6 + 2);
+        assert_eq!(fifo_dots(0, &[160]), 172);
+    }
+
+    #[test]
+    fn window_left_of_screen() {
+        // With WX below 7, the window's first pixels are off screen.
+        for ppu in render_both(LCDC_BG | LCDC_WINDOW, |ppu| {
+            for row in 0..8 {
+                set_tile_row(ppu, 1, row, 0xF0, 0xF0);
+            }
+            fill_map(ppu, 0x1C00, 1);
+            ppu.write(0x4B, 3);
+        }).iter() {
+            let colors: Vec<_> = (0..12).map(|x| pixel(ppu, x, 0)).collect();
+            assert_eq!(colors, [0, 0, 0, 0, 3, 3, 3, 3, 0, 0, 0, 0]);
+        }
+    }
+}
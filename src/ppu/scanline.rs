@@ -0,0 +1,82 @@
+use ppu::{shade, Ppu, SCREEN_WIDTH};
+use utils::BitOps;
+
+
+impl Ppu {
+    /// Draws the current line into the framebuffer, using the registers as
+    /// they are at the end of mode 3.
+    pub(super) fn render_scanline(&mut self) {
+        let ly = self.ly;
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+
+        // On monochrome hardware, LCDC bit 0 blanks both the background and
+        // the window.
+        if self.lcdc.get_bit(0) {
+            for x in 0..SCREEN_WIDTH {
+                let px = self.scx.wrapping_add(x as u8);
+                let py = self.scy.wrapping_add(ly);
+                bg_colors[x] = self.map_pixel(self.lcdc.get_bit(3), px, py);
+            }
+
+            let window_x = self.wx as i16 - 7;
+            if self.window_visible() {
+                for x in window_x.max(0)..SCREEN_WIDTH as i16 {
+                    let px = (x - window_x) as u8;
+                    bg_colors[x as usize] = self.map_pixel(
+                        self.lcdc.get_bit(6), px, self.window_line);
+                }
+                self.window_line += 1;
+            }
+        }
+
+        let line = ly as usize * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH {
+            self.framebuffer[line + x] = shade(self.bgp, bg_colors[x]);
+        }
+
+        if self.lcdc.get_bit(1) {
+            self.render_sprites(&bg_colors);
+        }
+    }
+
+    fn render_sprites(&mut self, bg_colors: &[u8; SCREEN_WIDTH]) {
+        let ly = self.ly as i16;
+        let mut sprites = self.sprites.clone();
+        // Sprites further left are drawn on top. Ties go to the earlier OAM
+        // entry, which the stable sort preserves.
+        sprites.sort_by_key(|sprite| sprite.x);
+
+        let line = self.ly as usize * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH as i16 {
+            for sprite in &sprites {
+                if x < sprite.x || sprite.x + 8 <= x {
+                    continue;
+                }
+                let color =
+                    self.sprite_pixel(sprite, x - sprite.x, ly - sprite.y);
+                if color == 0 {
+                    continue;
+                }
+                let behind_bg = sprite.flags.get_bit(7);
+                if !behind_bg || bg_colors[x as usize] == 0 {
+                    let palette = if sprite.flags.get_bit(4) {
+                        self.obp1
+                    } else {
+                        self.obp0
+                    };
+                    self.framebuffer[line + x as usize] =
+                        shade(palette, color);
+                }
+                break;
+            }
+        }
+    }
+
+    /// Returns the color index at the given position of the background or
+    /// window tile map.
+    fn map_pixel(&self, high_map: bool, x: u8, y: u8) -> u8 {
+        let map = if high_map { 0x1C00 } else { 0x1800 };
+        let tile = self.vram[map + (y as usize / 8) * 32 + x as usize / 8];
+        self.tile_pixel(self.tile_data_offset(tile), x % 8, y % 8)
+    }
+}
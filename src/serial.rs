@@ -0,0 +1,382 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use interrupts::{Interrupt, InterruptLine};
+use utils::BitOps;
+
+
+/// The other end of the link cable.
+///
+/// A transfer is clocked by one side. The side driving the clock calls
+/// `transfer`, while a side waiting on the external clock is told what it
+/// would send with `listen`, and polls for the other side to start a
+/// transfer.
+pub trait LinkCable: fmt::Debug {
+    /// Called when this side starts a transfer on its internal clock, with
+    /// the byte it sends. Returns the byte the other side sends back.
+    fn transfer(&mut self, out: u8) -> u8;
+
+    /// Called when this side starts or stops waiting for the other side to
+    /// clock a transfer. While waiting, `out` is the byte it would send.
+    fn listen(&mut self, _out: Option<u8>) {}
+
+    /// Called every M-cycle while this side waits on the external clock.
+    /// Returns the byte received once the other side clocked a transfer.
+    fn poll(&mut self) -> Option<u8> { None }
+}
+
+
+/// Captures every byte sent over the link. Nothing is sent back, so the
+/// game reads 0xFF, as with no cable connected.
+///
+/// Test ROMs commonly print their results over the serial port. Clones share
+/// the same buffer, so keep one to read the output.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureLink {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl CaptureLink {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The bytes sent so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.borrow().clone()
+    }
+
+    /// Returns the bytes sent so far, and clears the buffer.
+    pub fn take(&self) -> Vec<u8> {
+        self.buffer.borrow_mut().drain(..).collect()
+    }
+}
+
+impl LinkCable for CaptureLink {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.buffer.borrow_mut().push(out);
+        0xFF
+    }
+}
+
+
+/// A cable whose output is wired back to its input, so every transfer
+/// receives the byte it sent.
+#[derive(Clone, Debug, Default)]
+pub struct LoopbackLink;
+
+impl LinkCable for LoopbackLink {
+    fn transfer(&mut self, out: u8) -> u8 {
+        out
+    }
+}
+
+
+/// One end of a cable between two `Gameboy`s in the same process. Create
+/// both ends with `PairedLink::pair`, and connect one to each Game Boy.
+#[derive(Clone, Debug)]
+pub struct PairedLink {
+    cable: Rc<RefCell<[LinkEnd; 2]>>,
+    side: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct LinkEnd {
+    /// The byte this end would send, while it waits on the external clock.
+    listening: Option<u8>,
+    /// A byte clocked in by the other end, waiting to be polled.
+    received: Option<u8>,
+}
+
+impl PairedLink {
+    pub fn pair() -> (PairedLink, PairedLink) {
+        let cable = Rc::new(RefCell::new([LinkEnd::default(); 2]));
+        (PairedLink { cable: cable.clone(), side: 0 },
+         PairedLink { cable: cable, side: 1 })
+    }
+}
+
+impl LinkCable for PairedLink {
+    fn transfer(&mut self, out: u8) -> u8 {
+        let mut cable = self.cable.borrow_mut();
+        let other = &mut cable[1 - self.side];
+        match other.listening.take() {
+            Some(val) => {
+                other.received = Some(out);
+                val
+            }
+            // Nobody is listening on the other end.
+            None => 0xFF,
+        }
+    }
+
+    fn listen(&mut self, out: Option<u8>) {
+        self.cable.borrow_mut()[self.side].listening = out;
+    }
+
+    fn poll(&mut self) -> Option<u8> {
+        self.cable.borrow_mut()[self.side].received.take()
+    }
+}
+
+
+/// The serial port. With the internal clock it shifts out SB at 8192 Hz,
+/// one bit every 128 M-cycles, while shifting in the other side's byte.
+/// With the external clock it waits for the other side to clock a transfer.
+#[derive(Debug)]
+pub struct Serial {
+    interrupts: InterruptLine,
+    link: Option<Box<dyn LinkCable>>,
+    sb: u8,
+    sc: u8,
+    /// The byte being shifted in during an internally clocked transfer.
+    incoming: u8,
+    bits: u8,
+}
+
+impl Serial {
+    pub fn new(interrupts: InterruptLine) -> Self {
+        Serial {
+            interrupts: interrupts,
+            link: None,
+            sb: 0,
+            sc: 0,
+            incoming: 0,
+            bits: 0,
+        }
+    }
+
+    /// Plugs in the other end of the link cable, replacing any previous one.
+    pub fn connect(&mut self, mut link: Box<dyn LinkCable>) {
+        if self.waiting_external() {
+            link.listen(Some(self.sb));
+        }
+        self.link = Some(link);
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn LinkCable>> {
+        self.link.take()
+    }
+
+    /// Advances the serial port by one M-cycle, given the system counter
+    /// before and after it. The internal clock ticks on falling edges of
+    /// counter bit 8.
+    pub fn tick(&mut self, old_counter: u16, counter: u16) {
+        if !self.sc.get_bit(7) {
+            return;
+        }
+
+        if self.sc.get_bit(0) {
+            if old_counter.get_bit(8) && !counter.get_bit(8) {
+                self.shift_bit();
+            }
+        } else {
+            let received = self.link.as_mut().and_then(|link| link.poll());
+            if let Some(val) = received {
+                self.sb = val;
+                self.finish_transfer();
+            }
+        }
+    }
+
+    fn shift_bit(&mut self) {
+        let bit = self.incoming.get_bit(7 - self.bits);
+        self.sb = (self.sb << 1) | bit as u8;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.finish_transfer();
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        self.sc.set_bit(7, false);
+        self.bits = 0;
+        self.interrupts.request(Interrupt::Serial);
+    }
+
+    fn waiting_external(&self) -> bool {
+        self.sc.get_bit(7) && !self.sc.get_bit(0)
+    }
+
+    pub fn read(&self, port: u8) -> u8 {
+        match port {
+            0x01 => self.sb,
+            0x02 => self.sc,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, port: u8, val: u8) {
+        match port {
+            0x01 => self.sb = val,
+            0x02 => {
+                self.sc = val & SC_MASK;
+                self.bits = 0;
+                if self.sc.get_bit(7) && self.sc.get_bit(0) {
+                    // The whole byte is exchanged up front, and shifted into
+                    // SB a bit at a time.
+                    let sb = self.sb;
+                    self.incoming = match self.link {
+                        Some(ref mut link) => link.transfer(sb),
+                        None => 0xFF,
+                    };
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        let listening = if self.waiting_external() {
+            Some(self.sb)
+        } else {
+            None
+        };
+        if let Some(ref mut link) = self.link {
+            link.listen(listening);
+        }
+    }
+}
+
+
+const SC_MASK: u8 = 0b1000_0001;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serial_requested(interrupts: &InterruptLine) -> bool {
+        interrupts.flags().get_bit(Interrupt::Serial.bit())
+    }
+
+    /// Runs the serial port for the given number of M-cycles, advancing the
+    /// system counter as the timer does.
+    fn run(serial: &mut Serial, counter: &mut u16, cycles: u32) {
+        for _ in 0..cycles {
+            let old = *counter;
+            *counter = counter.wrapping_add(4);
+            serial.tick(old, *counter);
+        }
+    }
+
+    fn send(serial: &mut Serial, counter: &mut u16, val: u8) {
+        serial.write(0x01, val);
+        serial.write(0x02, 0x81);
+        run(serial, counter, 8 * 128);
+    }
+
+    #[test]
+    fn internal_clock_timing() {
+        let interrupts = InterruptLine::new();
+        let mut serial = Serial::new(interrupts.clone());
+        let mut counter = 0;
+        serial.write(0x01, 0xA5);
+        serial.write(0x02, 0x81);
+
+        // A bit is shifted on each falling edge of counter bit 8.
+        run(&mut serial, &mut counter, 127);
+        assert_eq!(serial.read(0x01), 0xA5);
+        run(&mut serial, &mut counter, 1);
+        assert_eq!(serial.read(0x01), 0x4B);
+        run(&mut serial, &mut counter, 3 * 128);
+        assert_eq!(serial.read(0x01), 0x5F);
+        run(&mut serial, &mut counter, 4 * 128 - 1);
+        assert_eq!(serial.read(0x02), 0x81);
+        assert!(!serial_requested(&interrupts));
+
+        run(&mut serial, &mut counter, 1);
+        assert_eq!(serial.read(0x01), 0xFF);
+        assert_eq!(serial.read(0x02), 0x01);
+        assert!(serial_requested(&interrupts));
+
+        // The edges come from the shared counter, so the first bit can come
+        // early.
+        let mut serial = Serial::new(InterruptLine::new());
+        let mut counter = 0x01FC;
+        serial.write(0x01, 0x00);
+        serial.write(0x02, 0x81);
+        run(&mut serial, &mut counter, 1);
+        assert_eq!(serial.read(0x01), 0x01);
+    }
+
+    #[test]
+    fn external_clock_without_cable() {
+        let interrupts = InterruptLine::new();
+        let mut serial = Serial::new(interrupts.clone());
+        let mut counter = 0;
+        serial.write(0x01, 0x42);
+        serial.write(0x02, 0x80);
+        run(&mut serial, &mut counter, 4096);
+        assert_eq!(serial.read(0x01), 0x42);
+        assert_eq!(serial.read(0x02), 0x80);
+        assert!(!serial_requested(&interrupts));
+    }
+
+    #[test]
+    fn capture_link() {
+        let mut serial = Serial::new(InterruptLine::new());
+        let capture = CaptureLink::new();
+        serial.connect(Box::new(capture.clone()));
+        let mut counter = 0;
+        // Test ROMs print their results a byte at a time.
+        for &byte in b"Passed\n".iter() {
+            send(&mut serial, &mut counter, byte);
+            assert_eq!(serial.read(0x01), 0xFF);
+        }
+        assert_eq!(capture.contents(), b"Passed\n");
+        assert_eq!(capture.take(), b"Passed\n");
+        assert!(capture.contents().is_empty());
+
+        // Writing SB alone sends nothing.
+        serial.write(0x01, b'x');
+        run(&mut serial, &mut counter, 8 * 128);
+        assert!(capture.contents().is_empty());
+    }
+
+    #[test]
+    fn loopback_link() {
+        let interrupts = InterruptLine::new();
+        let mut serial = Serial::new(interrupts.clone());
+        serial.connect(Box::new(LoopbackLink));
+        let mut counter = 0;
+        send(&mut serial, &mut counter, 0x3C);
+        assert_eq!(serial.read(0x01), 0x3C);
+        assert!(serial_requested(&interrupts));
+
+        // Once disconnected, transfers receive 0xFF.
+        assert!(serial.disconnect().is_some());
+        send(&mut serial, &mut counter, 0x3C);
+        assert_eq!(serial.read(0x01), 0xFF);
+    }
+
+    #[test]
+    fn paired_link() {
+        let (one, two) = PairedLink::pair();
+        let interrupts = [InterruptLine::new(), InterruptLine::new()];
+        let mut master = Serial::new(interrupts[0].clone());
+        let mut slave = Serial::new(interrupts[1].clone());
+        master.connect(Box::new(one));
+        slave.connect(Box::new(two));
+        let mut counter = 0;
+
+        // Without the other side listening, the master receives 0xFF.
+        send(&mut master, &mut counter, 0x11);
+        assert_eq!(master.read(0x01), 0xFF);
+
+        slave.write(0x01, 0x22);
+        slave.write(0x02, 0x80);
+        run(&mut slave, &mut counter, 16);
+        assert_eq!(slave.read(0x02), 0x80);
+
+        master.write(0x01, 0x11);
+        master.write(0x02, 0x81);
+        run(&mut slave, &mut counter, 1);
+        assert_eq!(slave.read(0x01), 0x11);
+        assert_eq!(slave.read(0x02), 0x00);
+        assert!(serial_requested(&interrupts[1]));
+
+        run(&mut master, &mut counter, 8 * 128);
+        assert_eq!(master.read(0x01), 0x22);
+        assert!(serial_requested(&interrupts[0]));
+    }
+}
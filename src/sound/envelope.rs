@@ -5,14 +5,22 @@ pub struct EnvelopeRegister {
     step_size: u8,
 }
 
+impl EnvelopeRegister {
+    /// Whether the channel's DAC is powered. Writing zero to the upper five
+    /// bits turns it off.
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.direction == Direction::Increase
+    }
+}
+
 impl From<u8> for EnvelopeRegister {
     fn from(byte: u8) -> Self {
         EnvelopeRegister {
-            initial_volume: (byte >> 4) & 0b111,
+            initial_volume: (byte >> 4) & 0b1111,
             direction: if byte >> 3 & 0b1 == 1 {
-                           Direction::Decrease
-                       } else {
                            Direction::Increase
+                       } else {
+                           Direction::Decrease
                        },
             step_size: byte & 0b111,
         }
@@ -24,8 +32,8 @@ impl Into<u8> for EnvelopeRegister {
         let mut out = 0;
         out |= self.initial_volume << 4;
         match self.direction {
-            Direction::Decrease => out |= 0x8,
-            Direction::Increase => (),
+            Direction::Increase => out |= 0x8,
+            Direction::Decrease => (),
         }
         out |= self.step_size;
         out
@@ -33,8 +41,48 @@ impl Into<u8> for EnvelopeRegister {
 }
 
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum Direction { Increase, Decrease }
 impl Default for Direction {
-    fn default() -> Self { Direction::Increase }
+    fn default() -> Self { Direction::Decrease }
+}
+
+
+/// The volume envelope. Every `step_size` ticks of its 64 Hz clock, the
+/// volume moves by one towards 0 or 15, where it stops.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Envelope {
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn trigger(&mut self, register: EnvelopeRegister) {
+        self.volume = register.initial_volume;
+        self.timer = period(register.step_size);
+    }
+
+    pub fn clock(&mut self, register: EnvelopeRegister) {
+        if register.step_size == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = period(register.step_size);
+            match register.direction {
+                Direction::Increase if self.volume < 15 => self.volume += 1,
+                Direction::Decrease if self.volume > 0 => self.volume -= 1,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Timers with a period of 0 are reloaded with 8 instead.
+fn period(step_size: u8) -> u8 {
+    if step_size == 0 { 8 } else { step_size }
 }
@@ -0,0 +1,33 @@
+/// The length counter, which silences a channel after a programmed time.
+/// It counts down at 256 Hz while enabled.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LengthCounter {
+    pub enabled: bool,
+    remaining: u16,
+}
+
+impl LengthCounter {
+    /// Loads the counter from a length register. The channel plays for
+    /// `max - length` steps.
+    pub fn load(&mut self, max: u16, length: u8) {
+        self.remaining = max - length as u16;
+    }
+
+    /// Triggering a channel whose counter ran out reloads it with the
+    /// maximum length.
+    pub fn trigger(&mut self, max: u16) {
+        if self.remaining == 0 {
+            self.remaining = max;
+        }
+    }
+
+    /// Clocks the counter. Returns true when it runs out, which disables the
+    /// channel.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.remaining > 0 {
+            self.remaining -= 1;
+            return self.remaining == 0;
+        }
+        false
+    }
+}
@@ -1,7 +1,11 @@
 mod envelope;
+mod length;
 mod registers;
+mod resampler;
 mod noise_channel;
 mod tone_channel;
 mod wav_channel;
 
 pub use self::registers::SoundRegisters;
+pub use self::resampler::{DEFAULT_AUDIO_BUFFER_SIZE,
+                          DEFAULT_SAMPLE_RATE};
@@ -1,6 +1,9 @@
-use sound::envelope::EnvelopeRegister;
+use sound::envelope::{Envelope, EnvelopeRegister};
+use sound::length::LengthCounter;
 use utils::{BitOps};
 
+/// The noise channel, which outputs the low bit of a linear feedback shift
+/// register.
 #[derive(Default, Debug)]
 pub struct NoiseChannel {
     sound_length: u8,
@@ -8,11 +11,102 @@ pub struct NoiseChannel {
     shift_clock_frequency: u8,
     regularity: Regularity,
     dividing_ratio: u8,
-    restart_sound: bool,
     use_sound_length: bool,
+
+    enabled: bool,
+    /// Clock cycles until the LFSR is next shifted.
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    volume: Envelope,
 }
 
 impl NoiseChannel {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// The current output level, from 0 to 15. The channel is high while
+    /// the low bit of the LFSR is clear.
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr.get_bit(0) {
+            0
+        } else {
+            self.volume.volume()
+        }
+    }
+
+    /// Advances the frequency timer by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        if !self.enabled {
+            return;
+        }
+        let mut remaining = cycles as u32 * 4;
+        while remaining > 0 {
+            let step = remaining.min(self.timer);
+            self.timer -= step;
+            remaining -= step;
+            if self.timer == 0 {
+                self.timer = self.period();
+                self.shift();
+            }
+        }
+    }
+
+    /// Clocks the length counter, at 256 Hz.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocks the volume envelope, at 64 Hz.
+    pub fn clock_envelope(&mut self) {
+        self.volume.clock(self.envelope);
+    }
+
+    /// Clears the channel when the APU is powered off. On monochrome
+    /// hardware the length counter keeps its value.
+    pub fn power_off(&mut self) {
+        let length = self.length;
+        *self = Default::default();
+        self.length = length;
+        self.length.enabled = false;
+    }
+
+    /// The clock cycles between LFSR shifts: the divisor selected by the
+    /// dividing ratio, shifted left by the shift clock frequency.
+    fn period(&self) -> u32 {
+        (DIVISORS[self.dividing_ratio as usize] as u32) <<
+            self.shift_clock_frequency
+    }
+
+    /// Shifts the LFSR once. The XOR of its two low bits is fed back into
+    /// bit 14, and also into bit 6 in 7-bit mode. The LFSR is never clocked
+    /// with a shift clock frequency of 14 or 15.
+    fn shift(&mut self) {
+        if self.shift_clock_frequency >= 14 {
+            return;
+        }
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if let Regularity::Regular = self.regularity {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.lfsr = LFSR_MASK;
+        self.volume.trigger(self.envelope);
+    }
+
     pub fn read(&self, reladdr: u8) -> u8 {
         match reladdr {
             1 => self.sound_length,
@@ -28,17 +122,24 @@ impl NoiseChannel {
             4 => {
                 let mut out = 0;
                 out.set_bit(6, self.use_sound_length);
-                out.set_bit(7, self.restart_sound);
                 out
             }
-            _ => panic!("Invalid addr for WavChannel::read: {:#X}", reladdr)
+            _ => unreachable!(),
         }
     }
 
     pub fn write(&mut self, reladdr: u8, val: u8) {
         match reladdr {
-            1 => self.sound_length = val,
-            2 => self.envelope = val.into(),
+            1 => {
+                self.sound_length = val & 0b11_1111;
+                self.length.load(64, self.sound_length);
+            }
+            2 => {
+                self.envelope = val.into();
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
             3 => {
                 self.shift_clock_frequency = (val >> 4) & 0b1111;
                 self.regularity = if val.get_bit(3) {
@@ -50,16 +151,101 @@ impl NoiseChannel {
             }
             4 => {
                 self.use_sound_length = val.get_bit(6);
-                self.restart_sound = val.get_bit(7);
+                self.length.enabled = self.use_sound_length;
+                if val.get_bit(7) {
+                    self.trigger();
+                }
             }
-            _ => panic!("Invalid addr for WavChannel::write: {:#X}", reladdr)
+            _ => unreachable!(),
         }
     }
 }
 
 
+/// The base period in clock cycles for each dividing ratio.
+const DIVISORS: [u8; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+const LFSR_MASK: u16 = 0x7FFF;
+
+
+/// The width of the LFSR. `Regular` shortens it to 7 bits, for a periodic,
+/// more tonal sound.
 #[derive(Copy, Clone, Debug)]
 enum Regularity { Regular, Irregular }
 impl Default for Regularity {
     fn default() -> Self { Regularity::Irregular }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggered(nr43: u8) -> NoiseChannel {
+        let mut channel = NoiseChannel::default();
+        channel.write(2, 0xF0);
+        channel.write(3, nr43);
+        channel.write(4, 0x80);
+        channel
+    }
+
+    fn states(channel: &mut NoiseChannel) -> Vec<u16> {
+        (0..24).map(|_| {
+            channel.shift();
+            channel.lfsr
+        }).collect()
+    }
+
+    #[test]
+    fn lfsr_15_bit() {
+        let mut channel = triggered(0x00);
+        assert_eq!(channel.lfsr, 0x7FFF);
+        assert_eq!(states(&mut channel), LFSR_15_BIT_STATES.to_vec());
+    }
+
+    #[test]
+    fn lfsr_7_bit() {
+        let mut channel = triggered(0x08);
+        assert_eq!(states(&mut channel), LFSR_7_BIT_STATES.to_vec());
+    }
+
+    #[test]
+    fn lfsr_clock() {
+        // A divisor of 16 shifted by 2 clocks the LFSR every 16 M-cycles.
+        let mut channel = triggered(0x21);
+        channel.tick(15);
+        assert_eq!(channel.lfsr, 0x7FFF);
+        channel.tick(1);
+        assert_eq!(channel.lfsr, 0x3FFF);
+        channel.tick(16);
+        assert_eq!(channel.lfsr, 0x1FFF);
+
+        // Shifts of 14 and 15 stop the clock.
+        let mut channel = triggered(0xE0);
+        channel.tick(255);
+        assert_eq!(channel.lfsr, 0x7FFF);
+    }
+
+    #[test]
+    fn output_follows_low_bit() {
+        let mut channel = triggered(0x00);
+        for _ in 0..14 {
+            channel.shift();
+        }
+        assert_eq!(channel.lfsr, 0x0001);
+        assert_eq!(channel.output(), 0);
+        channel.shift();
+        assert_eq!(channel.output(), 15);
+    }
+
+    const LFSR_15_BIT_STATES: [u16; 24] = [
+        0x3FFF, 0x1FFF, 0x0FFF, 0x07FF, 0x03FF, 0x01FF, 0x00FF, 0x007F,
+        0x003F, 0x001F, 0x000F, 0x0007, 0x0003, 0x0001, 0x4000, 0x2000,
+        0x1000, 0x0800, 0x0400, 0x0200, 0x0100, 0x0080, 0x0040, 0x0020,
+    ];
+
+    const LFSR_7_BIT_STATES: [u16; 24] = [
+        0x3FBF, 0x1F9F, 0x0F8F, 0x0787, 0x0383, 0x0181, 0x40C0, 0x2020,
+        0x1010, 0x0808, 0x0404, 0x0202, 0x4141, 0x60E0, 0x3030, 0x1818,
+        0x0C0C, 0x0606, 0x4343, 0x21A1, 0x50D0, 0x2828, 0x1414, 0x0A0A,
+    ];
+}
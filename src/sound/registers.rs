@@ -1,4 +1,6 @@
+use error::Result;
 use sound::noise_channel::NoiseChannel;
+use sound::resampler::Resampler;
 use sound::tone_channel::ToneChannel;
 use sound::wav_channel::WavChannel;
 use utils::BitOps;
@@ -11,6 +13,10 @@ pub struct SoundRegisters {
     noise_channel: NoiseChannel,
     sound_enable: SoundEnable,
     channel_control: ChannelControl,
+    /// The frame sequencer step, which clocks the length counters, sweep
+    /// and envelopes.
+    frame_step: u8,
+    resampler: Resampler,
 }
 
 impl SoundRegisters {
@@ -22,6 +28,126 @@ impl SoundRegisters {
         }
     }
 
+    /// Whether the APU is powered, from NR52 bit 7.
+    pub fn powered(&self) -> bool {
+        self.sound_enable.sound_enabled
+    }
+
+    /// Advances the channels by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        if self.powered() {
+            self.sweep_channel.tick(cycles);
+            self.tone_channel.tick(cycles);
+            self.wav_channel.tick(cycles);
+            self.noise_channel.tick(cycles);
+        }
+        let (left, right) = self.mix();
+        self.resampler.push(left, right, cycles);
+    }
+
+    /// Steps the 512 Hz frame sequencer, which is clocked by the system
+    /// counter. Length counters are clocked at 256 Hz, the sweep at 128 Hz
+    /// and envelopes at 64 Hz.
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered() {
+            return;
+        }
+        if self.frame_step % 2 == 0 {
+            self.sweep_channel.clock_length();
+            self.tone_channel.clock_length();
+            self.wav_channel.clock_length();
+            self.noise_channel.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.sweep_channel.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.sweep_channel.clock_envelope();
+            self.tone_channel.clock_envelope();
+            self.noise_channel.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /// Mixes the channels into the left and right outputs, each between -1
+    /// and 1. Each channel's DAC turns its 4-bit output into a voltage, and
+    /// NR51 pans it to either side. NR50 then scales each side.
+    pub fn mix(&self) -> (f32, f32) {
+        let channels = [
+            dac(self.sweep_channel.dac_enabled(), self.sweep_channel.output()),
+            dac(self.tone_channel.dac_enabled(), self.tone_channel.output()),
+            dac(self.wav_channel.dac_enabled(), self.wav_channel.output()),
+            dac(self.noise_channel.dac_enabled(), self.noise_channel.output()),
+        ];
+
+        let control = &self.channel_control;
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, sample) in channels.iter().enumerate() {
+            if control.output_to_so2[i] {
+                left += sample;
+            }
+            if control.output_to_so1[i] {
+                right += sample;
+            }
+        }
+        // SO2 is the left terminal and SO1 the right one.
+        let left_volume = (control.so2_volume + 1) as f32 / 8.0;
+        let right_volume = (control.so1_volume + 1) as f32 / 8.0;
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    /// Changes the output sample rate, in Hz. Buffered samples are dropped.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<()> {
+        let capacity = self.resampler.capacity();
+        self.resampler = try!(Resampler::new(sample_rate, capacity));
+        Ok(())
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.sample_rate()
+    }
+
+    /// Changes how many stereo frames are buffered before the oldest are
+    /// dropped. Buffered samples are dropped.
+    pub fn set_buffer_size(&mut self, frames: usize) {
+        self.resampler.set_capacity(frames);
+    }
+
+    /// The number of stereo frames ready to be drained.
+    pub fn samples_available(&self) -> usize {
+        self.resampler.available()
+    }
+
+    /// Moves buffered frames into `out`, as interleaved left and right
+    /// samples. Returns the number of frames written.
+    pub fn drain_samples(&mut self, out: &mut [i16]) -> usize {
+        self.resampler.drain(out)
+    }
+
+    /// Whether each channel is playing, as reported by NR52.
+    fn channel_status(&self) -> [bool; 4] {
+        [self.sweep_channel.enabled(), self.tone_channel.enabled(),
+         self.wav_channel.enabled(), self.noise_channel.enabled()]
+    }
+
+    /// Powers the APU on or off. Powering off clears every register but
+    /// wave RAM and the length counters, and powering on restarts the frame
+    /// sequencer.
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered() {
+            self.frame_step = 0;
+        } else if !on && self.powered() {
+            self.sweep_channel.power_off();
+            self.tone_channel.power_off();
+            self.wav_channel.power_off();
+            self.noise_channel.power_off();
+            self.channel_control = Default::default();
+        }
+        self.sound_enable.sound_enabled = on;
+    }
+
+    /// Reads from a sound port. Unused ports read as 0xFF.
     pub fn read(&self, port: u8) -> u8 {
         match port {
             0x10...0x14 => self.sweep_channel.read(port - 0x10),
@@ -29,48 +155,75 @@ impl SoundRegisters {
             0x1A...0x1E => self.wav_channel.read(port - 0x1A),
             0x20...0x23 => self.noise_channel.read(port - 0x20 + 1),
             0x24...0x25 => self.channel_control.read(port - 0x24),
-            0x26 => self.sound_enable.read(),
-            _ => panic!("Invalid port for SoundRegisters::read: {:#X}", port)
+            0x26 => self.sound_enable.read(self.channel_status()),
+            0x30...0x3F => self.wav_channel.read_wave_ram(port - 0x30),
+            _ => 0xFF,
         }
     }
 
+    /// Writes to a sound port. Writes to unused ports are ignored.
     pub fn write(&mut self, port: u8, val: u8) {
+        if !self.powered() {
+            // While powered off, only NR52, wave RAM and, on monochrome
+            // hardware, the length counters can be written.
+            match port {
+                0x11 => self.sweep_channel.write(1, val & 0b11_1111),
+                0x16 => self.tone_channel.write(1, val & 0b11_1111),
+                0x1B => self.wav_channel.write(1, val),
+                0x20 => self.noise_channel.write(1, val),
+                0x26 => self.set_power(val.get_bit(7)),
+                0x30...0x3F => {
+                    self.wav_channel.write_wave_ram(port - 0x30, val)
+                }
+                _ => (),
+            }
+            return;
+        }
+
         match port {
             0x10...0x14 => self.sweep_channel.write(port - 0x10, val),
             0x16...0x19 => self.tone_channel.write(port - 0x16 + 1, val),
             0x1A...0x1E => self.wav_channel.write(port - 0x1A, val),
             0x20...0x23 => self.noise_channel.write(port - 0x20 + 1, val),
             0x24...0x25 => self.channel_control.write(port - 0x24, val),
-            0x26 => self.sound_enable.write(val),
-            _ => panic!("Invalid port for SoundRegisters::write: {:#X}", port)
+            0x26 => self.set_power(val.get_bit(7)),
+            0x30...0x3F => self.wav_channel.write_wave_ram(port - 0x30, val),
+            _ => (),
         }
     }
 }
 
 
+/// Converts a channel's 4-bit output to a voltage between -1 and 1. A
+/// powered off DAC outputs nothing.
+fn dac(enabled: bool, sample: u8) -> f32 {
+    if enabled {
+        1.0 - sample as f32 / 7.5
+    } else {
+        0.0
+    }
+}
+
+
 #[derive(Debug, Default)]
 pub struct SoundEnable {
     sound_enabled: bool,
-    channel_on: [bool; 4],
 }
 
 impl SoundEnable {
-    pub fn read(&self) -> u8 {
+    /// Reads NR52, with the status of each channel in the low bits.
+    pub fn read(&self, channel_on: [bool; 4]) -> u8 {
         let mut out = 0;
         if self.sound_enabled {
             out |= 0b1 << 7;
         }
-        for i in 0..self.channel_on.len() {
-            if self.channel_on[i] {
+        for i in 0..channel_on.len() {
+            if channel_on[i] {
                 out |= 0b1 << i;
             }
         }
         out
     }
-
-    pub fn write(&mut self, val: u8) {
-        self.sound_enabled = (val >> 7) == 0;
-    }
 }
 
 
@@ -123,3 +276,263 @@ impl ChannelControl {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::Error;
+    use sound::resampler::{DEFAULT_AUDIO_BUFFER_SIZE, DEFAULT_SAMPLE_RATE};
+
+    /// Plays a fixed sequence on all four channels, draining `chunk` frames
+    /// at a time.
+    fn play(buffer_size: usize, chunk: usize) -> Vec<i16> {
+        let mut sound = SoundRegisters::new();
+        sound.set_buffer_size(buffer_size);
+        for &(port, val) in [
+            (0x26, 0x80), (0x24, 0x77), (0x25, 0xF3),
+            // A rising sweep on channel 1.
+            (0x10, 0x16), (0x11, 0x80), (0x12, 0xF3), (0x13, 0x00),
+            (0x14, 0x86),
+            // A short note on channel 2.
+            (0x16, 0x50), (0x17, 0xA0), (0x18, 0x83), (0x19, 0xC7),
+            // A sawtooth on the wave channel.
+            (0x30, 0x01), (0x31, 0x23), (0x32, 0x45), (0x33, 0x67),
+            (0x34, 0x89), (0x35, 0xAB), (0x36, 0xCD), (0x37, 0xEF),
+            (0x1A, 0x80), (0x1C, 0x40), (0x1D, 0x00), (0x1E, 0x85),
+            // Fading 7-bit noise.
+            (0x21, 0xF1), (0x22, 0x2B), (0x23, 0x80),
+        ].iter() {
+            sound.write(port, val);
+        }
+
+        let mut samples = Vec::new();
+        let mut buffer = vec![0; chunk * 2];
+        for cycle in 0..(1u32 << 18) {
+            sound.tick(1);
+            if cycle % 0x2000 == 0 {
+                sound.clock_frame_sequencer();
+            }
+            if sound.samples_available() >= chunk {
+                let frames = sound.drain_samples(&mut buffer);
+                samples.extend_from_slice(&buffer[..frames * 2]);
+            }
+        }
+        samples
+    }
+
+    /// Runs the APU for the given number of M-cycles, and returns every
+    /// left sample it produced.
+    fn left_samples(sound: &mut SoundRegisters, cycles: u32) -> Vec<i16> {
+        let mut samples = Vec::new();
+        let mut buffer = [0; 64];
+        for _ in 0..cycles {
+            sound.tick(1);
+            let frames = sound.drain_samples(&mut buffer);
+            samples.extend(buffer[..frames * 2].iter().step_by(2));
+        }
+        samples
+    }
+
+    fn powered_on() -> SoundRegisters {
+        let mut sound = SoundRegisters::new();
+        sound.write(0x26, 0x80);
+        sound
+    }
+
+    /// Steps the frame sequencer. Even steps clock the length counters.
+    fn step(sound: &mut SoundRegisters, steps: usize) {
+        for _ in 0..steps {
+            sound.clock_frame_sequencer();
+        }
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut sound = powered_on();
+        for port in 0x10..0x26 {
+            sound.write(port, 0xFF);
+        }
+        sound.write(0x26, 0x00);
+        assert_eq!(sound.read(0x26), 0x00);
+        sound.write(0x26, 0x80);
+        for port in (0x10..0x26).filter(|&port| port != 0x15 && port != 0x1F) {
+            assert_eq!(sound.read(port), 0x00, "port {:#04X}", port);
+        }
+    }
+
+    #[test]
+    fn writes_ignored_while_off() {
+        let mut sound = SoundRegisters::new();
+        for &port in [0x12, 0x14, 0x17, 0x1A, 0x1C, 0x22, 0x24, 0x25].iter() {
+            sound.write(port, 0xF0);
+            assert_eq!(sound.read(port), 0x00, "port {:#04X}", port);
+        }
+        sound.write(0x26, 0x80);
+        assert_eq!(sound.read(0x26), 0x80);
+    }
+
+    #[test]
+    fn wave_ram_survives_power_off() {
+        let mut sound = SoundRegisters::new();
+        sound.write(0x30, 0x12);
+        assert_eq!(sound.read(0x30), 0x12);
+        sound.write(0x26, 0x80);
+        sound.write(0x3F, 0x34);
+        sound.write(0x26, 0x00);
+        assert_eq!(sound.read(0x30), 0x12);
+        assert_eq!(sound.read(0x3F), 0x34);
+    }
+
+    #[test]
+    fn length_counters_survive_power_off() {
+        // Two steps of length are left when the APU is powered off.
+        let mut sound = powered_on();
+        sound.write(0x11, 0x3E);
+        sound.write(0x12, 0xF0);
+        sound.write(0x14, 0xC0);
+        sound.write(0x26, 0x00);
+        sound.write(0x26, 0x80);
+        sound.write(0x12, 0xF0);
+        sound.write(0x14, 0xC0);
+        step(&mut sound, 1);
+        assert_eq!(sound.read(0x26), 0x81);
+        step(&mut sound, 2);
+        assert_eq!(sound.read(0x26), 0x80);
+
+        // The length registers can be written while powered off.
+        let mut sound = SoundRegisters::new();
+        sound.write(0x16, 0xFF);
+        sound.write(0x20, 0x3F);
+        sound.write(0x26, 0x80);
+        for &(port, val) in
+                [(0x17, 0xF0), (0x19, 0xC0), (0x21, 0xF0), (0x23, 0xC0)]
+                .iter() {
+            sound.write(port, val);
+        }
+        assert_eq!(sound.read(0x26), 0x8A);
+        step(&mut sound, 1);
+        assert_eq!(sound.read(0x26), 0x80);
+    }
+
+    #[test]
+    fn channel_status() {
+        let mut sound = powered_on();
+        for &(ports, status) in [
+            ((0x12, 0x14), 0x81), ((0x17, 0x19), 0x83),
+            ((0x1A, 0x1E), 0x87), ((0x21, 0x23), 0x8F),
+        ].iter() {
+            sound.write(ports.0, 0xF0);
+            sound.write(ports.1, 0x80);
+            assert_eq!(sound.read(0x26), status);
+        }
+
+        // Turning a DAC off stops its channel.
+        sound.write(0x17, 0x00);
+        assert_eq!(sound.read(0x26), 0x8D);
+        // The status bits are read-only.
+        sound.write(0x26, 0x80);
+        assert_eq!(sound.read(0x26), 0x8D);
+        sound.write(0x26, 0x00);
+        assert_eq!(sound.read(0x26), 0x00);
+    }
+
+    #[test]
+    fn mixing() {
+        // An idle channel with its DAC on outputs a full voltage.
+        let mut sound = powered_on();
+        sound.write(0x17, 0x08);
+        assert_eq!(sound.mix(), (0.0, 0.0));
+
+        // NR51 pans channel 2 right, then left, and NR50 scales each side.
+        sound.write(0x25, 0x02);
+        sound.write(0x24, 0x07);
+        assert_eq!(sound.mix(), (0.0, 0.25));
+        sound.write(0x25, 0x20);
+        assert_eq!(sound.mix(), (0.03125, 0.0));
+        sound.write(0x24, 0x70);
+        assert_eq!(sound.mix(), (0.25, 0.0));
+        sound.write(0x25, 0x22);
+        sound.write(0x24, 0x33);
+        assert_eq!(sound.mix(), (0.125, 0.125));
+
+        // Channels add up, but one with its DAC off adds nothing.
+        sound.write(0x12, 0x08);
+        sound.write(0x25, 0x33);
+        sound.write(0x24, 0x77);
+        assert_eq!(sound.mix(), (0.5, 0.5));
+        sound.write(0x12, 0x00);
+        assert_eq!(sound.mix(), (0.25, 0.25));
+    }
+
+    #[test]
+    fn samples_follow_the_sample_rate() {
+        // 2^18 M-cycles is a quarter of a second, drained 512 frames at a
+        // time.
+        let samples = play(DEFAULT_AUDIO_BUFFER_SIZE, 512);
+        let frames = DEFAULT_SAMPLE_RATE as usize / 4;
+        assert_eq!(samples.len(), frames / 512 * 512 * 2);
+        assert!(samples.iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn silent_while_powered_off() {
+        let mut sound = SoundRegisters::new();
+        let samples = left_samples(&mut sound, 1 << 16);
+        assert!(samples.len() > 2000);
+        assert!(samples.iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn dc_offset_decays() {
+        // An idle channel with its DAC on holds the output at a quarter of
+        // full scale. The capacitor removes that within 100 milliseconds.
+        let mut sound = powered_on();
+        sound.write(0x24, 0x77);
+        sound.write(0x25, 0x22);
+        sound.write(0x17, 0x08);
+        let samples = left_samples(&mut sound, 1 << 17);
+        let peak = samples[..64].iter().cloned().max().unwrap();
+        assert!(peak > 6000 && peak < 10000, "peak {}", peak);
+        assert!(samples[4000..].iter().all(|&sample| sample.abs() <= 1));
+
+        // Powering off drops the output back by as much, which decays too.
+        sound.write(0x26, 0x00);
+        let samples = left_samples(&mut sound, 1 << 17);
+        let trough = samples[..64].iter().cloned().min().unwrap();
+        assert!(trough < -6000 && trough > -10000, "trough {}", trough);
+        assert!(samples[4000..].iter().all(|&sample| sample.abs() <= 1));
+    }
+
+    #[test]
+    fn samples_do_not_depend_on_buffering() {
+        // A small buffer wraps around many times.
+        let samples = play(DEFAULT_AUDIO_BUFFER_SIZE, 512);
+        let small = play(40, 7);
+        assert!(small.len() >= samples.len());
+        assert_eq!(small[..samples.len()], samples[..]);
+    }
+
+    #[test]
+    fn full_buffer_drops_oldest_frames() {
+        let mut sound = SoundRegisters::new();
+        sound.set_buffer_size(64);
+        for _ in 0..2000 {
+            sound.tick(255);
+        }
+        assert_eq!(sound.samples_available(), 64);
+    }
+
+    #[test]
+    fn invalid_sample_rates() {
+        let mut sound = SoundRegisters::new();
+        match sound.set_sample_rate(0) {
+            Err(Error::InvalidSampleRate { rate: 0 }) => (),
+            result => panic!("{:?}", result),
+        }
+        assert!(sound.set_sample_rate(2_000_000).is_err());
+        assert_eq!(sound.sample_rate(), DEFAULT_SAMPLE_RATE);
+        assert!(sound.set_sample_rate(48000).is_ok());
+        assert_eq!(sound.sample_rate(), 48000);
+    }
+}
@@ -0,0 +1,211 @@
+use std::f64::consts::PI;
+
+use error::{Error, Result};
+
+/// Turns the APU output, which changes at most once per M-cycle, into
+/// stereo samples at the output rate.
+///
+/// Like a blip buffer, every change in the output is added as a band-limited
+/// step at its exact position between output samples, so nothing above the
+/// output's Nyquist frequency aliases back into the audible range. The
+/// result then goes through a high-pass filter, which models the capacitor
+/// that removes the DC offset on the DMG's output.
+///
+/// Everything is computed in a fixed order from fixed-point time, so the
+/// same input always produces the same samples.
+#[derive(Debug)]
+pub struct Resampler {
+    sample_rate: u32,
+    /// The most frames kept before the oldest are dropped.
+    capacity: usize,
+    /// Output samples per M-cycle, in 32.32 fixed point.
+    factor: u64,
+    /// The time of the next M-cycle, in output samples from the oldest
+    /// unread frame, in 32.32 fixed point.
+    time: u64,
+    /// The changes in each side's level, which are summed when read out.
+    /// These are ring buffers, starting at the oldest unread frame.
+    deltas: [Vec<f32>; 2],
+    start: usize,
+    last: [f32; 2],
+    levels: [f32; 2],
+    /// The charge kept on the output capacitor, for each side.
+    capacitors: [f32; 2],
+    /// How much of its charge the capacitor keeps per output sample.
+    charge_factor: f32,
+    /// The band-limited step for each phase between two output samples,
+    /// stored as its differences.
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+impl Resampler {
+    /// Fails if the sample rate is zero, or higher than the rate the APU
+    /// output changes at.
+    pub fn new(sample_rate: u32, capacity: usize) -> Result<Self> {
+        if sample_rate == 0 || sample_rate as u64 > CLOCK_RATE {
+            return Err(Error::InvalidSampleRate { rate: sample_rate });
+        }
+        Ok(Resampler::with_valid_rate(sample_rate, capacity))
+    }
+
+    /// Keeps the rate, and drops any buffered frames.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        *self = Resampler::with_valid_rate(self.sample_rate, capacity);
+    }
+
+    fn with_valid_rate(sample_rate: u32, capacity: usize) -> Self {
+        let factor = ((sample_rate as u64) << 32) / CLOCK_RATE;
+        let charge_factor =
+            DMG_CHARGE_FACTOR.powf(CLOCK_RATE as f64 * 4.0 /
+                                   sample_rate as f64);
+        Resampler {
+            sample_rate: sample_rate,
+            capacity: capacity,
+            factor: factor,
+            time: 0,
+            deltas: [vec![0.0; capacity + KERNEL_WIDTH],
+                     vec![0.0; capacity + KERNEL_WIDTH]],
+            start: 0,
+            last: [0.0; 2],
+            levels: [0.0; 2],
+            capacitors: [0.0; 2],
+            charge_factor: charge_factor as f32,
+            kernel: step_kernel(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of stereo frames ready to be read.
+    pub fn available(&self) -> usize {
+        (self.time >> 32) as usize
+    }
+
+    /// Adds the output level of each side, held for the given number of
+    /// M-cycles. If the buffer fills up, the oldest frames are dropped.
+    pub fn push(&mut self, left: f32, right: f32, cycles: u8) {
+        for (side, &level) in [left, right].iter().enumerate() {
+            let delta = level - self.last[side];
+            if delta != 0.0 {
+                self.add_delta(side, delta);
+                self.last[side] = level;
+            }
+        }
+
+        self.time += self.factor * cycles as u64;
+        let available = self.available();
+        if available > self.capacity {
+            self.read_frames(None, available - self.capacity);
+        }
+    }
+
+    /// Reads frames into `out` as interleaved left and right samples.
+    /// Returns the number of frames read.
+    pub fn drain(&mut self, out: &mut [i16]) -> usize {
+        let frames = self.available().min(out.len() / 2);
+        self.read_frames(Some(out), frames);
+        frames
+    }
+
+    fn add_delta(&mut self, side: usize, delta: f32) {
+        let index = self.start + (self.time >> 32) as usize;
+        let phase = (self.time >> (32 - PHASE_BITS)) as usize % PHASES;
+        let deltas = &mut self.deltas[side];
+        let len = deltas.len();
+        for (i, step) in self.kernel[phase].iter().enumerate() {
+            deltas[(index + i) % len] += delta * step;
+        }
+    }
+
+    /// Sums the deltas of the oldest `frames` frames into output levels, and
+    /// filters them into `out` if given. The frames are then removed from
+    /// the buffer.
+    fn read_frames(&mut self, mut out: Option<&mut [i16]>, frames: usize) {
+        let len = self.deltas[0].len();
+        for frame in 0..frames {
+            let index = (self.start + frame) % len;
+            for side in 0..2 {
+                let delta = self.deltas[side][index];
+                self.deltas[side][index] = 0.0;
+                self.levels[side] += delta;
+                let level = self.levels[side] - self.capacitors[side];
+                self.capacitors[side] =
+                    self.levels[side] - level * self.charge_factor;
+
+                if let Some(ref mut out) = out {
+                    let sample = (level * i16::max_value() as f32)
+                        .max(i16::min_value() as f32)
+                        .min(i16::max_value() as f32);
+                    out[frame * 2 + side] = sample as i16;
+                }
+            }
+        }
+
+        self.start = (self.start + frames) % len;
+        self.time -= (frames as u64) << 32;
+    }
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Resampler::with_valid_rate(DEFAULT_SAMPLE_RATE,
+                                   DEFAULT_AUDIO_BUFFER_SIZE)
+    }
+}
+
+
+/// Computes a Blackman-windowed sinc step for each phase, as the
+/// differences between consecutive samples of the step.
+fn step_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let half = (KERNEL_WIDTH / 2) as f64;
+    (0..PHASES).map(|phase| {
+        let offset = phase as f64 / PHASES as f64;
+        let mut impulse = [0.0f64; KERNEL_WIDTH];
+        for (i, out) in impulse.iter_mut().enumerate() {
+            let x = i as f64 - half + 1.0 - offset;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+            };
+            let window = 0.42 + 0.5 * (PI * x / half).cos() +
+                0.08 * (2.0 * PI * x / half).cos();
+            *out = sinc * window.max(0.0);
+        }
+
+        // Normalize the step to a height of one.
+        let sum: f64 = impulse.iter().sum();
+        let mut kernel = [0.0f32; KERNEL_WIDTH];
+        for (out, value) in kernel.iter_mut().zip(impulse.iter()) {
+            *out = (value / sum) as f32;
+        }
+        kernel
+    }).collect()
+}
+
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// About 90 ms at the default sample rate.
+pub const DEFAULT_AUDIO_BUFFER_SIZE: usize = 4096;
+
+/// The rate at which the APU output is sampled, once per M-cycle.
+const CLOCK_RATE: u64 = 1 << 20;
+
+/// The samples each step is spread over.
+const KERNEL_WIDTH: usize = 16;
+/// Steps are positioned to 1/64th of an output sample.
+const PHASE_BITS: u64 = 6;
+const PHASES: usize = 1 << PHASE_BITS;
+/// The cutoff frequency of the steps, as a fraction of the output's Nyquist
+/// frequency. Keeping it a little low stops the edge of the band from
+/// aliasing.
+const CUTOFF: f64 = 0.9;
+
+/// How much charge the DMG's output capacitor keeps per T-cycle.
+const DMG_CHARGE_FACTOR: f64 = 0.999958;
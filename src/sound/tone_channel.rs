@@ -1,63 +1,245 @@
-use sound::envelope::EnvelopeRegister;
+use sound::envelope::{Envelope, EnvelopeRegister};
+use sound::length::LengthCounter;
 use utils::{BitOps, WordOps};
 
-// TODO: update the parsing into useful values, not just bytes
+/// A square wave channel: channel 1, which also has a frequency sweep, or
+/// channel 2.
 #[derive(Debug, Default)]
 pub struct ToneChannel {
     wave_duty: u8,
     sound_length: u8,
+    /// The 11-bit frequency. The waveform advances one of its eight steps
+    /// every `(2048 - frequency) * 4` clock cycles.
     frequency: u16,
-    restart_sound: bool,
     use_sound_length: bool,
     envelope: EnvelopeRegister,
-    sweep: Option<SweepRegister>,
+    sweep: Option<Sweep>,
+
+    enabled: bool,
+    /// Clock cycles until the waveform advances.
+    timer: u16,
+    duty_step: u8,
+    length: LengthCounter,
+    volume: Envelope,
 }
 
 impl ToneChannel {
     pub fn new(with_sweep: bool) -> Self {
-        let sweep = if with_sweep { Some(SweepRegister::default()) } else { None };
+        let sweep = if with_sweep { Some(Sweep::default()) } else { None };
         ToneChannel {
             sweep: sweep,
             ..Default::default()
         }
     }
 
+    /// Whether the channel is playing. It stops when its length runs out,
+    /// the sweep overflows, or its DAC is turned off.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether the channel's DAC is powered.
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// The current output level, from 0 to 15.
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let waveform = DUTY_WAVEFORMS[self.wave_duty as usize];
+        if waveform.get_bit(self.duty_step) { self.volume.volume() } else { 0 }
+    }
+
+    /// Advances the frequency timer by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        let mut remaining = cycles as u16 * 4;
+        while remaining > 0 {
+            if self.timer == 0 {
+                self.timer = self.period();
+                self.duty_step = (self.duty_step + 1) % 8;
+            }
+            let step = remaining.min(self.timer);
+            self.timer -= step;
+            remaining -= step;
+        }
+    }
+
+    /// Clocks the length counter, at 256 Hz.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocks the volume envelope, at 64 Hz.
+    pub fn clock_envelope(&mut self) {
+        self.volume.clock(self.envelope);
+    }
+
+    /// Clocks the frequency sweep, at 128 Hz.
+    pub fn clock_sweep(&mut self) {
+        if let Some(ref mut sweep) = self.sweep {
+            if !sweep.clock(&mut self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Clears the channel when the APU is powered off. On monochrome
+    /// hardware the length counter keeps its value.
+    pub fn power_off(&mut self) {
+        let length = self.length;
+        *self = ToneChannel::new(self.sweep.is_some());
+        self.length = length;
+        self.length.enabled = false;
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.volume.trigger(self.envelope);
+        if let Some(ref mut sweep) = self.sweep {
+            if !sweep.trigger(self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+
     pub fn read(&self, reladdr: u8) -> u8 {
         match reladdr {
-            0 if self.sweep.is_some() => self.sweep.unwrap().into(),
+            0 if self.sweep.is_some() => self.sweep.unwrap().register.into(),
             1 => (self.wave_duty << 6) | (self.sound_length),
             2 => self.envelope.into(),
             3 => self.frequency.get_lower(),
             4 => {
                 let mut out = self.frequency.get_upper();
                 out.set_bit(6, self.use_sound_length);
-                out.set_bit(7, self.restart_sound);
                 out
             }
-            _ => panic!("Invalid addr for ToneChannel::read: {:#X}", reladdr)
+            _ => unreachable!(),
         }
     }
 
     pub fn write(&mut self, reladdr: u8, val: u8) {
         match reladdr {
-            0 if self.sweep.is_some() => self.sweep = Some(val.into()),
+            0 if self.sweep.is_some() => {
+                let sweep = self.sweep.as_mut().unwrap();
+                if !sweep.set_register(val.into()) {
+                    self.enabled = false;
+                }
+            }
             1 => {
                 self.wave_duty = val >> 6;
-                self.sound_length = val & 0b1_1111;
+                self.sound_length = val & 0b11_1111;
+                self.length.load(64, self.sound_length);
+            }
+            2 => {
+                self.envelope = val.into();
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
             }
-            2 => self.envelope = val.into(),
             3 => self.frequency.set_lower(val),
             4 => {
-                self.frequency.set_upper(val);
+                self.frequency.set_upper(val & 0b111);
                 self.use_sound_length = val.get_bit(6);
-                self.restart_sound = val.get_bit(7);
+                self.length.enabled = self.use_sound_length;
+                if val.get_bit(7) {
+                    self.trigger();
+                }
             }
-            _ => panic!("Invalid addr for ToneChannel::write: {:#X}", reladdr)
+            _ => unreachable!(),
         }
     }
 }
 
 
+/// The eight step waveforms for each duty cycle, from the first step in the
+/// lowest bit.
+const DUTY_WAVEFORMS: [u8; 4] =
+    [0b1000_0000, 0b1000_0001, 0b1110_0001, 0b0111_1110];
+
+
+/// The frequency sweep of channel 1. It periodically shifts a shadow copy
+/// of the frequency and adds or subtracts it from itself.
+#[derive(Copy, Clone, Debug, Default)]
+struct Sweep {
+    register: SweepRegister,
+    shadow: u16,
+    timer: u8,
+    enabled: bool,
+    /// Whether a subtraction was computed since the last trigger. Switching
+    /// to addition afterwards disables the channel.
+    negated: bool,
+}
+
+impl Sweep {
+    /// Restarts the sweep. Returns false if the first calculation already
+    /// overflows, which disables the channel.
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow = frequency;
+        self.timer = self.register.period();
+        self.negated = false;
+        self.enabled =
+            self.register.sweep_time != 0 || self.register.sweep_shift != 0;
+        self.register.sweep_shift == 0 || self.calculate().is_some()
+    }
+
+    /// Returns false if the change disables the channel.
+    fn set_register(&mut self, register: SweepRegister) -> bool {
+        self.register = register;
+        !(self.negated && register.direction == SweepDirection::Up)
+    }
+
+    /// Clocks the sweep, updating the channel frequency when it changes.
+    /// Returns false if the frequency overflowed, which disables the
+    /// channel.
+    fn clock(&mut self, frequency: &mut u16) -> bool {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return true;
+        }
+        self.timer = self.register.period();
+        if !self.enabled || self.register.sweep_time == 0 {
+            return true;
+        }
+
+        let next = match self.calculate() {
+            Some(next) => next,
+            None => return false,
+        };
+        if self.register.sweep_shift != 0 {
+            self.shadow = next;
+            *frequency = next;
+            // The new frequency is checked for overflow again right away,
+            // but not used.
+            return self.calculate().is_some();
+        }
+        true
+    }
+
+    /// Computes the next frequency. Returns `None` if it overflows 11 bits.
+    fn calculate(&mut self) -> Option<u16> {
+        let delta = self.shadow >> self.register.sweep_shift;
+        let frequency = match self.register.direction {
+            SweepDirection::Up => self.shadow + delta,
+            SweepDirection::Down => {
+                self.negated = true;
+                self.shadow - delta
+            }
+        };
+        if frequency > 2047 { None } else { Some(frequency) }
+    }
+}
+
+
 #[derive(Copy, Clone, Debug, Default)]
 struct SweepRegister {
     sweep_time: u8,
@@ -65,6 +247,14 @@ struct SweepRegister {
     sweep_shift: u8,
 }
 
+impl SweepRegister {
+    /// The sweep timer period, in 128 Hz clocks. A sweep time of 0 reloads
+    /// the timer with 8.
+    fn period(&self) -> u8 {
+        if self.sweep_time == 0 { 8 } else { self.sweep_time }
+    }
+}
+
 impl From<u8> for SweepRegister {
     fn from(byte: u8) -> Self {
         SweepRegister {
@@ -93,8 +283,141 @@ impl Into<u8> for SweepRegister {
 }
 
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum SweepDirection { Up, Down }
 impl Default for SweepDirection {
     fn default() -> Self { SweepDirection::Up }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Triggers a channel at full volume with the given NR10 and
+    /// frequency.
+    fn triggered(nr10: u8, frequency: u16) -> ToneChannel {
+        let mut channel = ToneChannel::new(true);
+        channel.write(0, nr10);
+        channel.write(2, 0xF0);
+        channel.write(3, frequency as u8);
+        channel.write(4, 0x80 | (frequency >> 8) as u8);
+        channel
+    }
+
+    fn frequency(channel: &ToneChannel) -> u16 {
+        (channel.read(4) as u16 & 0b111) << 8 | channel.read(3) as u16
+    }
+
+    #[test]
+    fn duty_waveforms() {
+        let mut channel = triggered(0x00, 0);
+        for duty in 0..4 {
+            channel.write(1, duty << 6);
+            let waveform: Vec<u8> = (0..8).map(|step| {
+                channel.duty_step = step;
+                channel.output()
+            }).collect();
+            assert_eq!(waveform, DUTY_OUTPUTS[duty as usize].to_vec());
+        }
+    }
+
+    #[test]
+    fn frequency_timer() {
+        // A frequency of 2046 advances the waveform every 2 M-cycles.
+        let mut channel = triggered(0x00, 2046);
+        channel.tick(2);
+        assert_eq!(channel.duty_step, 0);
+        channel.tick(1);
+        assert_eq!(channel.duty_step, 1);
+        channel.tick(2);
+        assert_eq!(channel.duty_step, 2);
+        channel.tick(12);
+        assert_eq!(channel.duty_step, 0);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger() {
+        // 0x700 + (0x700 >> 1) overflows 11 bits.
+        assert!(!triggered(0x01, 0x700).enabled());
+        assert!(triggered(0x01, 0x500).enabled());
+        // With a shift of 0 nothing is calculated on trigger.
+        assert!(triggered(0x00, 0x7FF).enabled());
+    }
+
+    #[test]
+    fn sweep_overflow_on_clock() {
+        let mut channel = triggered(0x11, 0x100);
+        for &expected in [0x180, 0x240, 0x360, 0x510].iter() {
+            channel.clock_sweep();
+            assert_eq!(frequency(&channel), expected);
+            assert!(channel.enabled());
+        }
+        // The new frequency fits, but the check that follows overflows.
+        channel.clock_sweep();
+        assert_eq!(frequency(&channel), 0x798);
+        assert!(!channel.enabled());
+    }
+
+    #[test]
+    fn sweep_negate_then_up() {
+        // A subtraction on trigger, then switching to addition.
+        let mut channel = triggered(0x19, 0x400);
+        channel.write(0, 0x11);
+        assert!(!channel.enabled());
+
+        // Without a subtraction since the trigger, the switch is harmless.
+        let mut channel = triggered(0x18, 0x400);
+        channel.write(0, 0x11);
+        assert!(channel.enabled());
+    }
+
+    #[test]
+    fn length_expiry() {
+        let mut channel = ToneChannel::new(false);
+        channel.write(1, 0x3E);
+        channel.write(2, 0xF0);
+        channel.write(4, 0xC0);
+        channel.clock_length();
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+        assert_eq!(channel.output(), 0);
+
+        // Triggering with an expired length reloads the full 64 steps.
+        channel.write(4, 0xC0);
+        for _ in 0..63 {
+            channel.clock_length();
+        }
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+
+        // The length only counts down while enabled.
+        channel.write(4, 0x80);
+        for _ in 0..128 {
+            channel.clock_length();
+        }
+        assert!(channel.enabled());
+    }
+
+    #[test]
+    fn dac_off_disables() {
+        let mut channel = triggered(0x00, 0);
+        channel.write(2, 0x08);
+        assert!(channel.enabled());
+        channel.write(2, 0x00);
+        assert!(!channel.enabled());
+        channel.write(4, 0x80);
+        assert!(!channel.enabled());
+    }
+
+    /// The output at each step of the four duty cycles: 12.5%, 25%, 50% and
+    /// 75%.
+    const DUTY_OUTPUTS: [[u8; 8]; 4] = [
+        [0, 0, 0, 0, 0, 0, 0, 15],
+        [15, 0, 0, 0, 0, 0, 0, 15],
+        [15, 0, 0, 0, 0, 15, 15, 15],
+        [0, 15, 15, 15, 15, 15, 15, 0],
+    ];
+}
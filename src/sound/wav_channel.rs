@@ -1,21 +1,146 @@
+use sound::length::LengthCounter;
 use utils::{BitOps, WordOps};
 
+/// The wave channel, which plays back 32 4-bit samples from wave RAM.
 #[derive(Default, Debug)]
 pub struct WavChannel {
-    enabled: bool,
+    /// Whether the channel's DAC is powered, from NR30 bit 7.
+    dac_enabled: bool,
     sound_length: u8,
     level: OutputLevel,
+    /// The 11-bit frequency. The channel moves to the next sample every
+    /// `(2048 - frequency) * 2` clock cycles.
     frequency: u16,
-    restart_sound: bool,
     use_sound_length: bool,
+    wave_ram: [u8; 16],
+
+    enabled: bool,
+    /// Clock cycles until the next sample is read.
+    timer: u16,
+    /// The sample being played, from 0 to 31.
+    position: u8,
+    /// The wave RAM byte holding the current sample. It is only refreshed
+    /// when the channel moves to the next sample.
+    sample_byte: u8,
+    /// Whether the channel read wave RAM during the last M-cycle.
+    sample_read: bool,
+    length: LengthCounter,
 }
 
 impl WavChannel {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    /// The current output level, from 0 to 15.
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let sample = if self.position % 2 == 0 {
+            self.sample_byte >> 4
+        } else {
+            self.sample_byte & 0x0F
+        };
+        sample >> self.level.shift()
+    }
+
+    /// Advances the frequency timer by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        self.sample_read = false;
+        if !self.enabled {
+            return;
+        }
+        let mut remaining = cycles as u16 * 4;
+        while remaining > 0 {
+            let step = remaining.min(self.timer);
+            self.timer -= step;
+            remaining -= step;
+            if self.timer == 0 {
+                self.timer = self.period();
+                self.position = (self.position + 1) % 32;
+                self.sample_byte = self.wave_ram[self.position as usize / 2];
+                self.sample_read = true;
+            }
+        }
+    }
+
+    /// Clocks the length counter, at 256 Hz.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clears the channel when the APU is powered off. Wave RAM and, on
+    /// monochrome hardware, the length counter keep their values.
+    pub fn power_off(&mut self) {
+        let length = self.length;
+        let wave_ram = self.wave_ram;
+        *self = Default::default();
+        self.length = length;
+        self.length.enabled = false;
+        self.wave_ram = wave_ram;
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    fn trigger(&mut self) {
+        // On monochrome hardware, retriggering just as the channel reads a
+        // sample corrupts the start of wave RAM with the block being read.
+        if self.enabled && self.timer <= 2 {
+            let next = ((self.position + 1) % 32) as usize / 2;
+            if next < 4 {
+                self.wave_ram[0] = self.wave_ram[next];
+            } else {
+                let block = next & !0b11;
+                for i in 0..4 {
+                    self.wave_ram[i] = self.wave_ram[block + i];
+                }
+            }
+        }
+
+        self.enabled = self.dac_enabled;
+        self.length.trigger(256);
+        self.position = 0;
+        // The first sample is read after a short delay.
+        self.timer = self.period() + TRIGGER_DELAY;
+    }
+
+    /// Reads wave RAM. While the channel plays, the CPU can only reach the
+    /// byte the channel is reading, and only on the cycle it reads it.
+    /// Other reads return 0xFF.
+    pub fn read_wave_ram(&self, offset: u8) -> u8 {
+        if !self.enabled {
+            self.wave_ram[offset as usize]
+        } else if self.sample_read {
+            self.sample_byte
+        } else {
+            0xFF
+        }
+    }
+
+    /// Writes wave RAM, with the same restrictions as reads while the
+    /// channel plays.
+    pub fn write_wave_ram(&mut self, offset: u8, val: u8) {
+        if !self.enabled {
+            self.wave_ram[offset as usize] = val;
+        } else if self.sample_read {
+            self.wave_ram[self.position as usize / 2] = val;
+        }
+    }
+
     pub fn read(&self, reladdr: u8) -> u8 {
         match reladdr {
             0 => {
                 let mut out = 0;
-                out.set_bit(7, self.enabled);
+                out.set_bit(7, self.dac_enabled);
                 out
             }
             1 => self.sound_length,
@@ -24,35 +149,62 @@ impl WavChannel {
             4 => {
                 let mut out = self.frequency.get_upper();
                 out.set_bit(6, self.use_sound_length);
-                out.set_bit(7, self.restart_sound);
                 out
             }
-            _ => panic!("Invalid addr for WavChannel::read: {:#X}", reladdr)
+            _ => unreachable!(),
         }
     }
 
     pub fn write(&mut self, reladdr: u8, val: u8) {
         match reladdr {
-            0 => self.enabled = val.get_bit(7),
-            1 => self.sound_length = val,
+            0 => {
+                self.dac_enabled = val.get_bit(7);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => {
+                self.sound_length = val;
+                self.length.load(256, val);
+            }
             2 => self.level = val.into(),
             3 => self.frequency.set_lower(val),
             4 => {
-                self.frequency.set_upper(val);
+                self.frequency.set_upper(val & 0b111);
                 self.use_sound_length = val.get_bit(6);
-                self.restart_sound = val.get_bit(7);
+                self.length.enabled = self.use_sound_length;
+                if val.get_bit(7) {
+                    self.trigger();
+                }
             }
-            _ => panic!("Invalid addr for WavChannel::write: {:#X}", reladdr)
+            _ => unreachable!(),
         }
     }
 }
 
 
+/// The clock cycles between triggering the channel and it reading its first
+/// sample, on top of the usual period.
+const TRIGGER_DELAY: u16 = 6;
+
+
 #[derive(Copy, Clone, Debug)]
 enum OutputLevel {
     Muted, Full, Half, Quarter,
 }
 
+impl OutputLevel {
+    /// How far samples are shifted right at this level.
+    fn shift(&self) -> u8 {
+        match *self {
+            OutputLevel::Muted => 4,
+            OutputLevel::Full => 0,
+            OutputLevel::Half => 1,
+            OutputLevel::Quarter => 2,
+        }
+    }
+}
+
 impl Default for OutputLevel {
     fn default() -> Self {
         OutputLevel::Muted
@@ -81,3 +233,143 @@ impl Into<u8> for OutputLevel {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A channel at full volume whose wave RAM holds the samples 0 to 15,
+    /// twice.
+    fn loaded(frequency: u16) -> WavChannel {
+        let mut channel = WavChannel::default();
+        for i in 0..16 {
+            channel.write_wave_ram(i, WAVE_RAM[i as usize]);
+        }
+        channel.write(0, 0x80);
+        channel.write(2, 0x20);
+        channel.write(3, frequency as u8);
+        channel.write(4, (frequency >> 8) as u8);
+        channel
+    }
+
+    fn trigger(channel: &mut WavChannel) {
+        let nr34 = channel.read(4);
+        channel.write(4, 0x80 | nr34);
+    }
+
+    fn wave_ram(channel: &mut WavChannel) -> Vec<u8> {
+        channel.write(0, 0x00);
+        (0..16).map(|i| channel.read_wave_ram(i)).collect()
+    }
+
+    #[test]
+    fn plays_from_second_sample() {
+        // A frequency of 2046 reads a sample every M-cycle, after the delay
+        // on trigger.
+        let mut channel = loaded(2046);
+        trigger(&mut channel);
+        channel.tick(2);
+        assert_eq!(channel.output(), 0);
+        let samples: Vec<u8> = (0..16).map(|_| {
+            channel.tick(1);
+            channel.output()
+        }).collect();
+        let expected: Vec<u8> = (1..16).chain(0..1).collect();
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn output_levels() {
+        let mut channel = loaded(0);
+        trigger(&mut channel);
+        channel.sample_byte = 0xF6;
+        for &(nr32, high, low) in
+                [(0x00, 0, 0), (0x20, 15, 6), (0x40, 7, 3), (0x60, 3, 1)]
+                .iter() {
+            channel.write(2, nr32);
+            assert_eq!(channel.read(2), nr32);
+            channel.position = 0;
+            assert_eq!(channel.output(), high);
+            channel.position = 1;
+            assert_eq!(channel.output(), low);
+        }
+    }
+
+    #[test]
+    fn wave_ram_lockout() {
+        // A frequency of 2044 reads a sample every 2 M-cycles. The first is
+        // read on the fourth M-cycle after the trigger.
+        let mut channel = loaded(2044);
+        assert_eq!(channel.read_wave_ram(5), WAVE_RAM[5]);
+        trigger(&mut channel);
+        channel.tick(3);
+        assert_eq!(channel.read_wave_ram(5), 0xFF);
+        channel.write_wave_ram(5, 0x99);
+
+        // On the cycle a sample is read, any access reaches its byte.
+        channel.tick(1);
+        assert_eq!(channel.read_wave_ram(5), WAVE_RAM[0]);
+        channel.write_wave_ram(5, 0x77);
+        channel.tick(1);
+        assert_eq!(channel.read_wave_ram(0), 0xFF);
+
+        let mut expected = WAVE_RAM.to_vec();
+        expected[0] = 0x77;
+        assert_eq!(wave_ram(&mut channel), expected);
+    }
+
+    #[test]
+    fn retrigger_corruption() {
+        // Retriggering a cycle before sample 2 is read copies its byte over
+        // the first.
+        let mut channel = loaded(2044);
+        trigger(&mut channel);
+        channel.tick(5);
+        trigger(&mut channel);
+        let mut expected = WAVE_RAM.to_vec();
+        expected[0] = WAVE_RAM[1];
+        assert_eq!(wave_ram(&mut channel), expected);
+
+        // Past the first four bytes, the whole aligned block is copied.
+        let mut channel = loaded(2044);
+        trigger(&mut channel);
+        channel.tick(21);
+        trigger(&mut channel);
+        let mut expected = WAVE_RAM.to_vec();
+        expected[..4].copy_from_slice(&WAVE_RAM[4..8]);
+        assert_eq!(wave_ram(&mut channel), expected);
+
+        // Away from a read, nothing is corrupted.
+        let mut channel = loaded(2044);
+        trigger(&mut channel);
+        channel.tick(4);
+        trigger(&mut channel);
+        assert_eq!(wave_ram(&mut channel), WAVE_RAM.to_vec());
+    }
+
+    #[test]
+    fn length_expiry() {
+        let mut channel = loaded(0);
+        channel.write(1, 0xFE);
+        channel.write(4, 0xC0);
+        channel.clock_length();
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+
+        // Triggering with an expired length reloads the full 256 steps.
+        channel.write(4, 0xC0);
+        for _ in 0..255 {
+            channel.clock_length();
+        }
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+    }
+
+    const WAVE_RAM: [u8; 16] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
+    ];
+}
@@ -0,0 +1,259 @@
+use interrupts::{Interrupt, InterruptLine};
+use utils::{BitOps, WordOps};
+
+
+/// The timer. Both DIV and TIMA are driven by the system counter, which
+/// counts clock cycles. DIV exposes its upper byte, and TIMA counts falling
+/// edges of the counter bit selected by TAC.
+#[derive(Debug)]
+pub struct Timer {
+    interrupts: InterruptLine,
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload: Reload,
+}
+
+/// After TIMA overflows, it reads 0 for a cycle before it is reloaded from
+/// TMA and the interrupt is requested.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Reload {
+    Idle,
+    /// TIMA overflowed during the last cycle. Writing TIMA now cancels the
+    /// reload.
+    Pending,
+    /// TIMA was reloaded during this cycle. Writes to TIMA are ignored, and
+    /// writes to TMA also go through to TIMA.
+    Reloading,
+}
+
+impl Timer {
+    pub fn new(interrupts: InterruptLine) -> Self {
+        Timer {
+            interrupts: interrupts,
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reload: Reload::Idle,
+        }
+    }
+
+    /// The system counter, which other devices also derive their clocks
+    /// from.
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    /// Advances the timer by the given number of M-cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            match self.reload {
+                Reload::Idle => (),
+                Reload::Pending => {
+                    self.tima = self.tma;
+                    self.interrupts.request(Interrupt::Timer);
+                    self.reload = Reload::Reloading;
+                }
+                Reload::Reloading => self.reload = Reload::Idle,
+            }
+            let counter = self.counter.wrapping_add(CYCLES_PER_TICK);
+            self.set_counter(counter);
+        }
+    }
+
+    /// Resets the system counter, as writing DIV or executing STOP does.
+    pub fn reset_divider(&mut self) {
+        self.set_counter(0);
+    }
+
+    pub fn read(&self, port: u8) -> u8 {
+        match port {
+            0x04 => self.counter.get_upper(),
+            0x05 => self.tima,
+            0x06 => self.tma,
+            0x07 => self.tac,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, port: u8, val: u8) {
+        match port {
+            0x04 => self.reset_divider(),
+            0x05 => match self.reload {
+                Reload::Pending => {
+                    self.tima = val;
+                    self.reload = Reload::Idle;
+                }
+                Reload::Reloading => (),
+                Reload::Idle => self.tima = val,
+            },
+            0x06 => {
+                self.tma = val;
+                if self.reload == Reload::Reloading {
+                    self.tima = val;
+                }
+            }
+            0x07 => {
+                // Changing TAC can also drop the timer signal, which counts
+                // as a falling edge.
+                let signal = self.signal();
+                self.tac = val & TAC_MASK;
+                self.detect_edge(signal);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Updates the system counter and clocks TIMA if the selected bit falls.
+    /// Resetting the counter can cause such an edge too.
+    fn set_counter(&mut self, counter: u16) {
+        let signal = self.signal();
+        self.counter = counter;
+        self.detect_edge(signal);
+    }
+
+    fn detect_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.signal() {
+            self.increment();
+        }
+    }
+
+    /// The input TIMA counts falling edges of: the enable bit ANDed with the
+    /// selected counter bit.
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            0b11 => 7,
+            _ => unreachable!(),
+        };
+        self.tac.get_bit(2) && self.counter.get_bit(bit)
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload = Reload::Pending;
+        }
+    }
+}
+
+
+/// The system counter advances by four clock cycles every M-cycle.
+const CYCLES_PER_TICK: u16 = 4;
+const TAC_MASK: u8 = 0b111;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interrupts::Interrupt;
+
+    /// A timer counting falling edges of counter bit 3, which it sees every
+    /// four M-cycles.
+    fn fast_timer() -> (Timer, InterruptLine) {
+        let interrupts = InterruptLine::new();
+        let mut timer = Timer::new(interrupts.clone());
+        timer.write(0x07, 0b101);
+        (timer, interrupts)
+    }
+
+    /// Runs the timer until TIMA overflows, leaving the reload pending.
+    fn overflow(timer: &mut Timer) {
+        timer.write(0x05, 0xFF);
+        timer.write(0x06, 0x42);
+        timer.tick(4);
+        assert_eq!(timer.read(0x05), 0x00);
+    }
+
+    fn timer_requested(interrupts: &InterruptLine) -> bool {
+        interrupts.flags().get_bit(Interrupt::Timer.bit())
+    }
+
+    #[test]
+    fn div_write_while_bit_high_increments_tima() {
+        let (mut timer, _) = fast_timer();
+        timer.tick(2);
+        assert!(timer.counter().get_bit(3));
+        timer.write(0x04, 0);
+        assert_eq!(timer.read(0x05), 1);
+        assert_eq!(timer.counter(), 0);
+
+        // With the bit low, there is no edge.
+        timer.tick(1);
+        timer.write(0x04, 0);
+        assert_eq!(timer.read(0x05), 1);
+    }
+
+    #[test]
+    fn tac_write_causes_falling_edge() {
+        let (mut timer, _) = fast_timer();
+        timer.tick(2);
+        // Disabling the timer drops the signal.
+        timer.write(0x07, 0b001);
+        assert_eq!(timer.read(0x05), 1);
+
+        // So does selecting a bit that is low.
+        timer.write(0x07, 0b101);
+        timer.write(0x07, 0b100);
+        assert_eq!(timer.read(0x05), 2);
+
+        // Enabling the timer with the bit high is no edge.
+        timer.write(0x07, 0b001);
+        timer.write(0x07, 0b101);
+        assert_eq!(timer.read(0x05), 2);
+    }
+
+    #[test]
+    fn overflow_reloads_after_a_cycle() {
+        let (mut timer, interrupts) = fast_timer();
+        overflow(&mut timer);
+        assert!(!timer_requested(&interrupts));
+        timer.tick(1);
+        assert_eq!(timer.read(0x05), 0x42);
+        assert!(timer_requested(&interrupts));
+    }
+
+    #[test]
+    fn tima_write_while_pending_cancels_reload() {
+        let (mut timer, interrupts) = fast_timer();
+        overflow(&mut timer);
+        timer.write(0x05, 0x10);
+        timer.tick(1);
+        assert_eq!(timer.read(0x05), 0x10);
+        assert!(!timer_requested(&interrupts));
+    }
+
+    #[test]
+    fn tima_write_while_reloading_is_ignored() {
+        let (mut timer, _) = fast_timer();
+        overflow(&mut timer);
+        timer.tick(1);
+        timer.write(0x05, 0x10);
+        assert_eq!(timer.read(0x05), 0x42);
+
+        // The next cycle accepts writes again.
+        timer.tick(1);
+        timer.write(0x05, 0x10);
+        assert_eq!(timer.read(0x05), 0x10);
+    }
+
+    #[test]
+    fn tma_write_while_reloading_sets_tima() {
+        let (mut timer, _) = fast_timer();
+        overflow(&mut timer);
+        timer.tick(1);
+        timer.write(0x06, 0x24);
+        assert_eq!(timer.read(0x05), 0x24);
+        assert_eq!(timer.read(0x06), 0x24);
+
+        // Afterwards, TMA only takes effect on the next reload.
+        timer.tick(1);
+        timer.write(0x06, 0x99);
+        assert_eq!(timer.read(0x05), 0x24);
+    }
+}
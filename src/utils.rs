@@ -53,10 +53,10 @@ impl WordOps for u16 {
     }
 
     fn set_lower(&mut self, val: u8) {
-        *self = (*self & 0xF0) | (val as u16);
+        *self = (*self & 0xFF00) | (val as u16);
     }
 
     fn set_upper(&mut self, val: u8) {
-        *self = (*self & 0x0F) | ((val as u16) << 8);
+        *self = (*self & 0x00FF) | ((val as u16) << 8);
     }
 }